@@ -0,0 +1,67 @@
+use crate::bindings::*;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::raw::c_int;
+
+// bindgen only exports the generic `sockaddr` types, the IPv4 and IPv6
+// variants and the address families come from libc for the target platform.
+pub(crate) use libc::{AF_INET, AF_INET6};
+
+/// A `SocketAddr` laid out as a C `sockaddr`, to hand over to ATS.
+pub(crate) struct RawSockAddr {
+    storage: libc::sockaddr_storage,
+}
+
+impl RawSockAddr {
+    pub(crate) fn new(addr: &SocketAddr) -> RawSockAddr {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+        // Fields are set one by one since some platforms add their own, like
+        // `sin_len` on the BSDs, which stay zeroed.
+        match addr {
+            SocketAddr::V4(v4) => {
+                let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+                sin.sin_family = AF_INET as libc::sa_family_t;
+                sin.sin_port = v4.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+            }
+            SocketAddr::V6(v6) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = v6.port().to_be();
+                sin6.sin6_flowinfo = v6.flowinfo().to_be();
+                sin6.sin6_addr.s6_addr = v6.ip().octets();
+                sin6.sin6_scope_id = v6.scope_id();
+            }
+        }
+
+        RawSockAddr { storage }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const sockaddr {
+        &self.storage as *const libc::sockaddr_storage as *const sockaddr
+    }
+}
+
+/// Reads a `sockaddr` returned by ATS. Null pointers and families other than
+/// IPv4 and IPv6 give `None`.
+pub(crate) unsafe fn socket_addr(addr: *const sockaddr) -> Option<SocketAddr> {
+    if addr.is_null() {
+        return None;
+    }
+
+    match c_int::from((*addr).sa_family) {
+        AF_INET => {
+            let sin = (addr as *const libc::sockaddr_in).read_unaligned();
+            let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        }
+        AF_INET6 => {
+            let sin6 = (addr as *const libc::sockaddr_in6).read_unaligned();
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            let port = u16::from_be(sin6.sin6_port);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, port, u32::from_be(sin6.sin6_flowinfo), sin6.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,73 @@
+use crate::bindings::*;
+use crate::header::HttpHeaderRef;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+type AltInfoGetter = unsafe extern "C" fn(TSHttpAltInfo, *mut TSMBuffer, *mut TSMLoc) -> TSReturnCode;
+type SelectAltFn = Box<dyn Fn(&AltInfo) -> f32 + Send + Sync>;
+
+/// A cached alternate being considered for a request, see `on_select_alt`.
+pub struct AltInfo {
+    info: TSHttpAltInfo,
+}
+
+impl AltInfo {
+    pub fn as_raw(&self) -> TSHttpAltInfo {
+        self.info
+    }
+
+    /// The request of the client.
+    pub fn client_request(&self) -> Option<HttpHeaderRef<'_>> {
+        self.header(TSHttpAltInfoClientReqGet)
+    }
+
+    /// The request the alternate was cached for.
+    pub fn cached_request(&self) -> Option<HttpHeaderRef<'_>> {
+        self.header(TSHttpAltInfoCachedReqGet)
+    }
+
+    /// The cached response of the alternate.
+    pub fn cached_response(&self) -> Option<HttpHeaderRef<'_>> {
+        self.header(TSHttpAltInfoCachedRespGet)
+    }
+
+    fn header(&self, get: AltInfoGetter) -> Option<HttpHeaderRef<'_>> {
+        let mut bufp: TSMBuffer = ptr::null_mut();
+        let mut hdrp: TSMLoc = ptr::null_mut();
+
+        unsafe {
+            if get(self.info, &mut bufp, &mut hdrp) != TSReturnCode_TS_SUCCESS {
+                return None;
+            }
+            Some(HttpHeaderRef::from_raw(bufp, hdrp))
+        }
+    }
+}
+
+/// Ranks cached alternates with `handler`, from `TS_HTTP_SELECT_ALT_HOOK`.
+///
+/// The handler is called for each alternate of a cache hit and returns its
+/// quality, from 0.0 (unacceptable) to 1.0; ATS serves the best one. The
+/// headers borrow the `AltInfo`, clone them to keep them past the call.
+/// Handlers run concurrently on the net threads and must not block.
+pub fn on_select_alt<F: Fn(&AltInfo) -> f32 + Send + Sync + 'static>(handler: F) {
+    let handler: SelectAltFn = Box::new(handler);
+
+    unsafe {
+        let contp = TSContCreate(Some(handle_select_alt_event), ptr::null_mut());
+        TSContDataSet(contp, Box::into_raw(Box::new(handler)) as *mut c_void);
+        TSHttpHookAdd(TSHttpHookID_TS_HTTP_SELECT_ALT_HOOK, contp);
+    }
+}
+
+unsafe extern "C" fn handle_select_alt_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    if event != TSEvent_TS_EVENT_HTTP_SELECT_ALT {
+        return 0;
+    }
+
+    let handler = &*(TSContDataGet(contp) as *const SelectAltFn);
+    let alt = AltInfo { info: edata as TSHttpAltInfo };
+    TSHttpAltInfoQualitySet(alt.info, handler(&alt));
+
+    0
+}
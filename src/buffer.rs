@@ -0,0 +1,125 @@
+use crate::bindings::*;
+use std::os::raw::c_void;
+use std::slice;
+
+/// An owned `TSIOBuffer`, destroyed together with its readers on drop.
+pub struct IOBuffer {
+    buf: TSIOBuffer,
+}
+
+impl IOBuffer {
+    pub fn new() -> IOBuffer {
+        IOBuffer { buf: unsafe { TSIOBufferCreate() } }
+    }
+
+    pub fn as_raw(&self) -> TSIOBuffer {
+        self.buf
+    }
+
+    /// Allocates a new reader that starts at the current write position.
+    pub fn reader(&self) -> IOBufferReader {
+        IOBufferReader::from_raw(unsafe { TSIOBufferReaderAlloc(self.buf) })
+    }
+
+    /// Appends `data` to the buffer, returning the number of bytes written.
+    pub fn write(&self, data: &[u8]) -> i64 {
+        if data.is_empty() {
+            return 0;
+        }
+
+        unsafe { TSIOBufferWrite(self.buf, data.as_ptr() as *const c_void, data.len() as i64) }
+    }
+
+    /// Copies up to `len` bytes from `reader` without consuming them.
+    pub fn copy_from(&self, reader: IOBufferReader, len: i64) -> i64 {
+        unsafe { TSIOBufferCopy(self.buf, reader.as_raw(), len, 0) }
+    }
+}
+
+impl Default for IOBuffer {
+    fn default() -> IOBuffer {
+        IOBuffer::new()
+    }
+}
+
+impl Drop for IOBuffer {
+    fn drop(&mut self) {
+        unsafe { TSIOBufferDestroy(self.buf) }
+    }
+}
+
+/// A borrowed `TSIOBufferReader`. Readers are owned by their buffer.
+#[derive(Debug, Copy, Clone)]
+pub struct IOBufferReader {
+    reader: TSIOBufferReader,
+}
+
+impl IOBufferReader {
+    pub fn from_raw(reader: TSIOBufferReader) -> IOBufferReader {
+        IOBufferReader { reader }
+    }
+
+    pub fn as_raw(&self) -> TSIOBufferReader {
+        self.reader
+    }
+
+    pub fn avail(&self) -> i64 {
+        unsafe { TSIOBufferReaderAvail(self.reader) }
+    }
+
+    pub fn consume(&self, len: i64) {
+        unsafe { TSIOBufferReaderConsume(self.reader, len) }
+    }
+
+    /// Appends up to `max` available bytes to `out` without consuming them.
+    pub fn read_to(&self, out: &mut Vec<u8>, max: i64) -> i64 {
+        let mut remaining = max;
+
+        unsafe {
+            let mut block = TSIOBufferReaderStart(self.reader);
+            while !block.is_null() && remaining > 0 {
+                let mut avail: i64 = 0;
+                let start = TSIOBufferBlockReadStart(block, self.reader, &mut avail);
+                let len = avail.min(remaining);
+                if !start.is_null() && len > 0 {
+                    out.extend_from_slice(slice::from_raw_parts(start as *const u8, len as usize));
+                    remaining -= len;
+                }
+
+                block = TSIOBufferBlockNext(block);
+            }
+        }
+
+        max - remaining
+    }
+
+    /// Copies available bytes into `buf` without consuming them.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+
+        unsafe {
+            let mut block = TSIOBufferReaderStart(self.reader);
+            while !block.is_null() && copied < buf.len() {
+                let mut avail: i64 = 0;
+                let start = TSIOBufferBlockReadStart(block, self.reader, &mut avail);
+                let len = (avail as usize).min(buf.len() - copied);
+                if !start.is_null() && len > 0 {
+                    buf[copied..copied + len].copy_from_slice(slice::from_raw_parts(start as *const u8, len));
+                    copied += len;
+                }
+
+                block = TSIOBufferBlockNext(block);
+            }
+        }
+
+        copied
+    }
+
+    /// Reads and consumes every available byte.
+    pub fn drain(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let read = self.read_to(&mut out, self.avail());
+        self.consume(read);
+        out
+    }
+}
@@ -0,0 +1,765 @@
+use crate::bindings::*;
+use crate::buffer::{IOBuffer, IOBufferReader};
+use crate::cache_info::{system_time, CacheHttpInfo};
+use crate::executor::lock;
+use crate::ts_url::TsUrl;
+use crate::txn::Transaction;
+use crate::vconn::{VConn, Vio};
+use std::error::Error;
+use std::fmt;
+use std::future::poll_fn;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// `ECACHE_NO_DOC` from I_CacheDefs.h, sent negated with the failure events.
+const ECACHE_NO_DOC: i32 = 20400;
+
+/// The kind of object a `CacheKey` refers to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheDataType {
+    None,
+    Http,
+    Other,
+}
+
+impl CacheDataType {
+    fn as_raw(self) -> TSCacheDataType {
+        match self {
+            CacheDataType::None => TSCacheDataType_TS_CACHE_DATA_TYPE_NONE,
+            CacheDataType::Http => TSCacheDataType_TS_CACHE_DATA_TYPE_HTTP,
+            CacheDataType::Other => TSCacheDataType_TS_CACHE_DATA_TYPE_OTHER,
+        }
+    }
+}
+
+/// A key into the ATS cache.
+pub struct CacheKey {
+    key: TSCacheKey,
+}
+
+// SAFETY: a cache key is plain data owned by this handle.
+unsafe impl Send for CacheKey {}
+
+impl CacheKey {
+    pub fn new() -> CacheKey {
+        CacheKey { key: unsafe { TSCacheKeyCreate() } }
+    }
+
+    /// A key whose digest is computed from `data`.
+    pub fn from_data(data: &[u8]) -> Result<CacheKey, String> {
+        let key = CacheKey::new();
+        key.set_digest(data)?;
+        Ok(key)
+    }
+
+    /// A key whose digest is computed from `url`, as for HTTP objects.
+    pub fn from_url(url: &TsUrl) -> Result<CacheKey, String> {
+        let key = CacheKey::new();
+        key.set_digest_from_url(url)?;
+        Ok(key)
+    }
+
+    pub fn as_raw(&self) -> TSCacheKey {
+        self.key
+    }
+
+    pub fn set_digest(&self, data: &[u8]) -> Result<(), String> {
+        match unsafe { TSCacheKeyDigestSet(self.key, data.as_ptr() as *const c_char, data.len() as c_int) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err("unable to set cache key digest".to_string()),
+        }
+    }
+
+    pub fn set_digest_from_url(&self, url: &TsUrl) -> Result<(), String> {
+        match unsafe { TSCacheKeyDigestFromUrlSet(self.key, url.as_raw().1) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set cache key digest from {}", url)),
+        }
+    }
+
+    /// Sets the host name used to pick the cache volume, see `hosting.config`.
+    pub fn set_host_name(&self, host: &str) -> Result<(), String> {
+        match unsafe { TSCacheKeyHostNameSet(self.key, host.as_ptr() as *const c_char, host.len() as c_int) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set cache key host name {}", host)),
+        }
+    }
+
+    pub fn set_data_type(&self, data_type: CacheDataType) -> Result<(), String> {
+        match unsafe { TSCacheKeyDataTypeSet(self.key, data_type.as_raw()) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set cache key data type {:?}", data_type)),
+        }
+    }
+
+    /// Keeps objects written with this key in the cache for at least `pin`.
+    pub fn set_pinned(&self, pin: Duration) -> Result<(), String> {
+        match unsafe { TSCacheKeyPinnedSet(self.key, pin.as_secs() as time_t) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err("unable to pin cache key".to_string()),
+        }
+    }
+}
+
+impl Default for CacheKey {
+    fn default() -> CacheKey {
+        CacheKey::new()
+    }
+}
+
+impl Drop for CacheKey {
+    fn drop(&mut self) {
+        unsafe { TSCacheKeyDestroy(self.key) };
+    }
+}
+
+/// The outcome of the cache lookup of a transaction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheLookupStatus {
+    Miss,
+    HitStale,
+    HitFresh,
+    Skipped,
+}
+
+impl CacheLookupStatus {
+    fn from_raw(status: c_int) -> Option<CacheLookupStatus> {
+        match status as TSCacheLookupResult {
+            TSCacheLookupResult_TS_CACHE_LOOKUP_MISS => Some(CacheLookupStatus::Miss),
+            TSCacheLookupResult_TS_CACHE_LOOKUP_HIT_STALE => Some(CacheLookupStatus::HitStale),
+            TSCacheLookupResult_TS_CACHE_LOOKUP_HIT_FRESH => Some(CacheLookupStatus::HitFresh),
+            TSCacheLookupResult_TS_CACHE_LOOKUP_SKIPPED => Some(CacheLookupStatus::Skipped),
+            _ => None,
+        }
+    }
+
+    fn as_raw(self) -> c_int {
+        let status = match self {
+            CacheLookupStatus::Miss => TSCacheLookupResult_TS_CACHE_LOOKUP_MISS,
+            CacheLookupStatus::HitStale => TSCacheLookupResult_TS_CACHE_LOOKUP_HIT_STALE,
+            CacheLookupStatus::HitFresh => TSCacheLookupResult_TS_CACHE_LOOKUP_HIT_FRESH,
+            CacheLookupStatus::Skipped => TSCacheLookupResult_TS_CACHE_LOOKUP_SKIPPED,
+        };
+        status as c_int
+    }
+}
+
+impl Transaction {
+    /// Stores and looks up the response under `url` instead of the request
+    /// URL. Must be called before `TS_HTTP_CACHE_LOOKUP_COMPLETE_HOOK`,
+    /// usually from remap or `TS_HTTP_READ_REQUEST_HDR_HOOK`.
+    pub fn set_cache_url(&self, url: &str) -> Result<(), String> {
+        match unsafe { TSCacheUrlSet(self.as_raw(), url.as_ptr() as *const c_char, url.len() as c_int) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set cache url {}", url)),
+        }
+    }
+
+    /// The URL the cache lookup uses.
+    pub fn cache_lookup_url(&self) -> Option<TsUrl> {
+        let url = TsUrl::new();
+        let (bufp, urlp) = url.as_raw();
+
+        match unsafe { TSHttpTxnCacheLookupUrlGet(self.as_raw(), bufp, urlp) } {
+            TSReturnCode_TS_SUCCESS => Some(url),
+            _ => None,
+        }
+    }
+
+    /// Replaces the URL the cache lookup uses, with the same constraints as
+    /// `set_cache_url`.
+    pub fn set_cache_lookup_url(&self, url: &TsUrl) -> Result<(), String> {
+        let (bufp, urlp) = url.as_raw();
+
+        match unsafe { TSHttpTxnCacheLookupUrlSet(self.as_raw(), bufp, urlp) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set cache lookup url {}", url)),
+        }
+    }
+
+    /// The result of the cache lookup, available from
+    /// `TS_HTTP_CACHE_LOOKUP_COMPLETE_HOOK` on.
+    pub fn cache_lookup_status(&self) -> Option<CacheLookupStatus> {
+        let mut status: c_int = 0;
+        match unsafe { TSHttpTxnCacheLookupStatusGet(self.as_raw(), &mut status) } {
+            TSReturnCode_TS_SUCCESS => CacheLookupStatus::from_raw(status),
+            _ => None,
+        }
+    }
+
+    /// Overrides the result of the cache lookup, e.g. turning a fresh hit
+    /// into `HitStale` to force revalidation. Only valid in
+    /// `TS_HTTP_CACHE_LOOKUP_COMPLETE_HOOK`, and a miss cannot be turned
+    /// into a hit.
+    pub fn set_cache_lookup_status(&self, status: CacheLookupStatus) -> Result<(), String> {
+        match unsafe { TSHttpTxnCacheLookupStatusSet(self.as_raw(), status.as_raw()) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set cache lookup status {:?}", status)),
+        }
+    }
+
+    /// Makes ATS treat the request as cacheable or not, regardless of its
+    /// method and headers. Call from remap or `TS_HTTP_READ_REQUEST_HDR_HOOK`.
+    pub fn set_request_cacheable(&self, cacheable: bool) {
+        unsafe { TSHttpTxnReqCacheableSet(self.as_raw(), cacheable as c_int) }
+    }
+
+    /// Makes ATS treat the origin response as cacheable or not, regardless of
+    /// its headers. Call from `TS_HTTP_READ_RESPONSE_HDR_HOOK`.
+    pub fn set_response_cacheable(&self, cacheable: bool) {
+        unsafe { TSHttpTxnRespCacheableSet(self.as_raw(), cacheable as c_int) }
+    }
+
+    /// Prevents the origin response from being written to the cache. Call
+    /// from `TS_HTTP_READ_RESPONSE_HDR_HOOK`.
+    pub fn set_server_response_no_store(&self, no_store: bool) -> Result<(), String> {
+        match unsafe { TSHttpTxnServerRespNoStoreSet(self.as_raw(), no_store as c_int) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err("unable to set server response no-store".to_string()),
+        }
+    }
+
+    /// Whether ATS would cache the origin response of the transaction, given
+    /// its configuration and the request and response headers. Call from
+    /// `TS_HTTP_READ_RESPONSE_HDR_HOOK`.
+    pub fn is_cacheable(&self) -> bool {
+        unsafe { TSHttpTxnIsCacheable(self.as_raw(), ptr::null_mut(), ptr::null_mut()) != 0 }
+    }
+
+    /// Stores the response with an explicit expiry time, overriding its
+    /// cache headers. Call from `TS_HTTP_READ_RESPONSE_HDR_HOOK`.
+    pub fn overwrite_expire_time(&self, expires: SystemTime) {
+        let secs = expires.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        unsafe { TSHttpTxnOverwriteExpireTime(self.as_raw(), secs as time_t) }
+    }
+
+    /// When the cached response was received from the origin, on a cache hit.
+    /// Available from `TS_HTTP_CACHE_LOOKUP_COMPLETE_HOOK` on.
+    pub fn cached_response_time(&self) -> Option<SystemTime> {
+        let mut time: time_t = 0;
+        match unsafe { TSHttpTxnCachedRespTimeGet(self.as_raw(), &mut time) } {
+            TSReturnCode_TS_SUCCESS => Some(system_time(time)),
+            _ => None,
+        }
+    }
+
+    /// Writes the header changed through `cached_response_modifiable` back
+    /// to the cache. Call on a cache hit, from
+    /// `TS_HTTP_CACHE_LOOKUP_COMPLETE_HOOK` or `TS_HTTP_READ_CACHE_HDR_HOOK`.
+    pub fn update_cached_object(&self) -> Result<(), String> {
+        match unsafe { TSHttpTxnUpdateCachedObject(self.as_raw()) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err("unable to update cached object".to_string()),
+        }
+    }
+
+    /// How many cache lookups the transaction made, more than one when ATS
+    /// retried the lookup, e.g. after a redirect.
+    pub fn cache_lookup_count(&self) -> Option<u32> {
+        let mut count: c_int = 0;
+        match unsafe { TSHttpTxnCacheLookupCountGet(self.as_raw(), &mut count) } {
+            TSReturnCode_TS_SUCCESS => Some(count as u32),
+            _ => None,
+        }
+    }
+}
+
+/// An error from the cache API. Failures carry the negative ATS error code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheError {
+    /// The cache is not initialized yet, see `Cache::is_ready`.
+    NotReady,
+    /// `TS_EVENT_CACHE_OPEN_READ_FAILED` for a reason other than a miss.
+    ReadFailed(i32),
+    /// `TS_EVENT_CACHE_OPEN_WRITE_FAILED`, e.g. another writer holds the key.
+    WriteFailed(i32),
+    /// `TS_EVENT_CACHE_REMOVE_FAILED` for a reason other than a miss.
+    RemoveFailed(i32),
+    /// `TS_EVENT_CACHE_SCAN_FAILED`, or 0 if the scan could not be started.
+    ScanFailed(i32),
+    /// The object could not be transferred once opened.
+    Io,
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheError::NotReady => write!(f, "cache is not ready"),
+            CacheError::ReadFailed(code) => write!(f, "cache read failed: {}", code),
+            CacheError::WriteFailed(code) => write!(f, "cache write failed: {}", code),
+            CacheError::RemoveFailed(code) => write!(f, "cache remove failed: {}", code),
+            CacheError::ScanFailed(code) => write!(f, "cache scan failed: {}", code),
+            CacheError::Io => write!(f, "cache transfer failed"),
+        }
+    }
+}
+
+impl Error for CacheError {}
+
+/// The ATS cache used as a key-value store. Objects are opaque bytes,
+/// independent of the HTTP objects ATS caches for transactions.
+///
+/// All operations are futures, to be awaited from `spawn`.
+pub struct Cache;
+
+impl Cache {
+    /// Whether the cache is initialized and accepts operations.
+    pub fn is_ready() -> bool {
+        let mut ready: c_int = 0;
+        unsafe { TSCacheReady(&mut ready) == TSReturnCode_TS_SUCCESS && ready != 0 }
+    }
+
+    /// Opens the object stored under `key`, resolving to `None` on a miss.
+    pub async fn read(key: &CacheKey) -> Result<Option<CacheBody>, CacheError> {
+        if !Cache::is_ready() {
+            return Err(CacheError::NotReady);
+        }
+
+        let op = CacheOp::start(|contp| unsafe { TSCacheRead(contp, key.as_raw()) });
+        match op.opened().await {
+            Ok(()) => {}
+            Err(code) if code == -ECACHE_NO_DOC => return Ok(None),
+            Err(code) => return Err(CacheError::ReadFailed(code)),
+        }
+
+        let len = op.with_state(|state| unsafe {
+            let vc = state.vc.ok_or(CacheError::Io)?;
+            let len = TSVConnCacheObjectSizeGet(vc.as_raw());
+            if len == 0 {
+                // A zero byte read would never get a completion event.
+                state.done = true;
+                return Ok(len);
+            }
+            let buffer = IOBuffer::new();
+            state.reader = Some(buffer.reader());
+            state.vio = Some(vc.read(op.contp, &buffer, len));
+            state.buffer = Some(buffer);
+            Ok(len)
+        })?;
+
+        Ok(Some(CacheBody { op, len }))
+    }
+
+    /// Stores `data` under `key`, replacing any previous object.
+    pub async fn write(key: &CacheKey, data: &[u8]) -> Result<(), CacheError> {
+        if !Cache::is_ready() {
+            return Err(CacheError::NotReady);
+        }
+
+        let op = CacheOp::start(|contp| unsafe { TSCacheWrite(contp, key.as_raw()) });
+        op.opened().await.map_err(CacheError::WriteFailed)?;
+
+        op.with_state(|state| unsafe {
+            let vc = state.vc.ok_or(CacheError::Io)?;
+            let buffer = IOBuffer::new();
+            let reader = buffer.reader();
+            buffer.write(data);
+            state.vio = Some(vc.write(op.contp, reader, data.len() as i64));
+            state.buffer = Some(buffer);
+            state.writing = true;
+            Ok(())
+        })?;
+
+        poll_fn(|cx| {
+            op.with_state(|state| {
+                if state.error {
+                    // Abort so that a partial object is not committed.
+                    if let Some(vc) = state.vc.take() {
+                        vc.abort(1);
+                    }
+                    return Poll::Ready(Err(CacheError::Io));
+                }
+                if state.done {
+                    return Poll::Ready(Ok(()));
+                }
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
+    /// Removes the object stored under `key`, resolving to `false` if there
+    /// was none.
+    pub async fn remove(key: &CacheKey) -> Result<bool, CacheError> {
+        if !Cache::is_ready() {
+            return Err(CacheError::NotReady);
+        }
+
+        let op = CacheOp::start(|contp| unsafe { TSCacheRemove(contp, key.as_raw()) });
+        match op.opened().await {
+            Ok(()) => Ok(true),
+            Err(code) if code == -ECACHE_NO_DOC => Ok(false),
+            Err(code) => Err(CacheError::RemoveFailed(code)),
+        }
+    }
+}
+
+/// The body of an object opened with `Cache::read`.
+pub struct CacheBody {
+    op: CacheOp,
+    len: i64,
+}
+
+impl CacheBody {
+    /// The size of the object in bytes.
+    pub fn len(&self) -> i64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Resolves to the next chunk of the body, or `None` once it is complete.
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, CacheError> {
+        poll_fn(|cx| {
+            self.op.with_state(|state| {
+                let reader = match state.reader {
+                    Some(reader) => reader,
+                    None => return Poll::Ready(Ok(None)),
+                };
+
+                if reader.avail() > 0 {
+                    let chunk = reader.drain();
+                    if let Some(vio) = state.vio {
+                        vio.reenable();
+                    }
+                    return Poll::Ready(Ok(Some(chunk)));
+                }
+                if state.error {
+                    return Poll::Ready(Err(CacheError::Io));
+                }
+                if state.done {
+                    return Poll::Ready(Ok(None));
+                }
+
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
+    /// Reads the whole body.
+    pub async fn bytes(mut self) -> Result<Vec<u8>, CacheError> {
+        let mut body = Vec::with_capacity(self.len.max(0) as usize);
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+/// A cache operation driven by its own continuation.
+struct CacheOp {
+    contp: TSCont,
+    mutex: TSMutex,
+    state: Arc<Mutex<CacheState>>,
+}
+
+// SAFETY: the continuation and its buffers are only used while holding the
+// continuation mutex.
+unsafe impl Send for CacheOp {}
+
+#[derive(Default)]
+struct CacheState {
+    action: Option<TSAction>,
+    opened: Option<Result<(), i32>>,
+    vc: Option<VConn>,
+    buffer: Option<IOBuffer>,
+    reader: Option<IOBufferReader>,
+    vio: Option<Vio>,
+    done: bool,
+    error: bool,
+    writing: bool,
+    waker: Option<Waker>,
+}
+
+// SAFETY: see `CacheOp`.
+unsafe impl Send for CacheState {}
+
+impl CacheState {
+    fn wake(&mut self) {
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+impl CacheOp {
+    fn start<S: FnOnce(TSCont) -> TSAction>(start: S) -> CacheOp {
+        let state = Arc::new(Mutex::new(CacheState::default()));
+
+        unsafe {
+            let mutex = TSMutexCreate();
+            let contp = TSContCreate(Some(handle_cache_event), mutex);
+            TSContDataSet(contp, Arc::into_raw(state.clone()) as *mut c_void);
+
+            // The cache may answer synchronously, so only the ATS lock is
+            // held while the operation starts.
+            TSMutexLock(mutex);
+            let action = start(contp);
+            {
+                let mut state = lock(&state);
+                if state.opened.is_none() && TSActionDone(action) == 0 {
+                    state.action = Some(action);
+                }
+            }
+            TSMutexUnlock(mutex);
+
+            CacheOp { contp, mutex, state }
+        }
+    }
+
+    fn with_state<T, F: FnOnce(&mut CacheState) -> T>(&self, f: F) -> T {
+        unsafe {
+            TSMutexLock(self.mutex);
+            let result = f(&mut lock(&self.state));
+            TSMutexUnlock(self.mutex);
+            result
+        }
+    }
+
+    /// Waits for the open or remove event, failures carry the error code.
+    async fn opened(&self) -> Result<(), i32> {
+        poll_fn(|cx| {
+            self.with_state(|state| match state.opened {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl Drop for CacheOp {
+    fn drop(&mut self) {
+        unsafe {
+            TSMutexLock(self.mutex);
+            {
+                let mut state = lock(&self.state);
+                if let Some(action) = state.action.take() {
+                    TSActionCancel(action);
+                }
+                if let Some(vc) = state.vc.take() {
+                    // Closing would commit whatever part of a dropped write
+                    // reached the cache.
+                    if state.writing && !state.done {
+                        vc.abort(1);
+                    } else {
+                        vc.close();
+                    }
+                }
+            }
+            TSMutexUnlock(self.mutex);
+
+            drop(Arc::from_raw(TSContDataGet(self.contp) as *const Mutex<CacheState>));
+            TSContDataSet(self.contp, ptr::null_mut());
+            TSContDestroy(self.contp);
+        }
+    }
+}
+
+unsafe extern "C" fn handle_cache_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    let ptr = TSContDataGet(contp) as *const Mutex<CacheState>;
+    if ptr.is_null() {
+        return 0;
+    }
+    let mut state = lock(&*ptr);
+
+    match event {
+        TSEvent_TS_EVENT_CACHE_OPEN_READ | TSEvent_TS_EVENT_CACHE_OPEN_WRITE => {
+            state.action = None;
+            state.vc = Some(VConn::from_raw(edata as TSVConn));
+            state.opened = Some(Ok(()));
+        }
+        TSEvent_TS_EVENT_CACHE_REMOVE => {
+            state.action = None;
+            state.opened = Some(Ok(()));
+        }
+        TSEvent_TS_EVENT_CACHE_OPEN_READ_FAILED
+        | TSEvent_TS_EVENT_CACHE_OPEN_WRITE_FAILED
+        | TSEvent_TS_EVENT_CACHE_REMOVE_FAILED => {
+            state.action = None;
+            state.opened = Some(Err(edata as isize as i32));
+        }
+        TSEvent_TS_EVENT_VCONN_READ_READY | TSEvent_TS_EVENT_VCONN_WRITE_READY => {}
+        TSEvent_TS_EVENT_VCONN_READ_COMPLETE | TSEvent_TS_EVENT_VCONN_WRITE_COMPLETE | TSEvent_TS_EVENT_VCONN_EOS => {
+            state.done = true;
+        }
+        _ => {
+            state.error = true;
+        }
+    }
+
+    state.wake();
+    0
+}
+
+/// What a cache scan does after visiting an object.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScanAction {
+    Continue,
+    Stop,
+    /// Deletes the object and continues with the next one.
+    Delete,
+    /// Runs a blocked operation again, only meaningful from
+    /// `CacheScanVisitor::operation`. Visits treat it as `Continue`.
+    Retry,
+}
+
+impl ScanAction {
+    fn as_raw(self) -> TSCacheScanResult {
+        match self {
+            ScanAction::Continue => TSCacheScanResult_TS_CACHE_SCAN_RESULT_CONTINUE,
+            ScanAction::Stop => TSCacheScanResult_TS_CACHE_SCAN_RESULT_DONE,
+            ScanAction::Delete => TSCacheScanResult_TS_CACHE_SCAN_RESULT_DELETE,
+            ScanAction::Retry => TSCacheScanResult_TS_CACHE_SCAN_RESULT_RETRY,
+        }
+    }
+}
+
+/// A delete requested by a visitor that did not happen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScanOperation {
+    /// `TS_EVENT_CACHE_SCAN_OPERATION_BLOCKED`: the object is busy, e.g.
+    /// being written. Continuing skips the object, `Retry` tries the
+    /// operation again.
+    Blocked,
+    /// `TS_EVENT_CACHE_SCAN_OPERATION_FAILED`.
+    Failed,
+}
+
+/// Receives the objects of a `CacheScan`.
+pub trait CacheScanVisitor: Send {
+    /// Called for each cached object. The info and its headers are borrowed
+    /// for the call, clone them to keep them.
+    fn visit(&mut self, info: &CacheHttpInfo) -> ScanAction;
+
+    /// Called when the operation requested for the last object did not run.
+    fn operation(&mut self, _outcome: ScanOperation) -> ScanAction {
+        ScanAction::Continue
+    }
+
+    /// Called exactly once, when the scan ends. Stopping counts as success.
+    fn done(&mut self, _result: Result<(), CacheError>) {}
+}
+
+/// Walks the objects in the cache with `TSCacheScan`.
+pub struct CacheScan {
+    host: Option<String>,
+    kb_per_second: i32,
+}
+
+impl CacheScan {
+    pub fn new() -> CacheScan {
+        CacheScan { host: None, kb_per_second: 0 }
+    }
+
+    /// Only scans the volume assigned to `host` in `hosting.config`.
+    pub fn host(mut self, host: &str) -> CacheScan {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    /// Limits the disk bandwidth used by the scan, 0 means no limit.
+    pub fn kb_per_second(mut self, kb_per_second: i32) -> CacheScan {
+        self.kb_per_second = kb_per_second;
+        self
+    }
+
+    /// Starts the scan in the background, reporting to `visitor`.
+    pub fn start<V: CacheScanVisitor + 'static>(self, visitor: V) {
+        let mut visitor: Box<dyn CacheScanVisitor> = Box::new(visitor);
+
+        if !Cache::is_ready() {
+            visitor.done(Err(CacheError::NotReady));
+            return;
+        }
+
+        let key = match self.host {
+            Some(host) => {
+                let key = CacheKey::new();
+                if let Err(e) = key.set_host_name(&host) {
+                    crate::ts_error(&e);
+                    visitor.done(Err(CacheError::ScanFailed(0)));
+                    return;
+                }
+                Some(key)
+            }
+            None => None,
+        };
+        let raw_key = key.as_ref().map(|k| k.as_raw()).unwrap_or(ptr::null_mut());
+
+        unsafe {
+            let mutex = TSMutexCreate();
+            let contp = TSContCreate(Some(handle_scan_event), mutex);
+            let scan = Box::new(ScanState { visitor, _key: key });
+            TSContDataSet(contp, Box::into_raw(scan) as *mut c_void);
+
+            TSMutexLock(mutex);
+            TSCacheScan(contp, raw_key, self.kb_per_second);
+            TSMutexUnlock(mutex);
+        }
+    }
+}
+
+impl Default for CacheScan {
+    fn default() -> CacheScan {
+        CacheScan::new()
+    }
+}
+
+struct ScanState {
+    visitor: Box<dyn CacheScanVisitor>,
+    // Kept alive until the scan completes.
+    _key: Option<CacheKey>,
+}
+
+unsafe extern "C" fn handle_scan_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    let scan = &mut *(TSContDataGet(contp) as *mut ScanState);
+
+    let result = match event {
+        TSEvent_TS_EVENT_CACHE_SCAN => return TSCacheScanResult_TS_CACHE_SCAN_RESULT_CONTINUE as c_int,
+        TSEvent_TS_EVENT_CACHE_SCAN_OBJECT => {
+            let info = CacheHttpInfo::from_raw(edata as TSCacheHttpInfo);
+            return match scan.visitor.visit(&info) {
+                ScanAction::Retry => ScanAction::Continue,
+                action => action,
+            }
+            .as_raw() as c_int;
+        }
+        TSEvent_TS_EVENT_CACHE_SCAN_OPERATION_BLOCKED | TSEvent_TS_EVENT_CACHE_SCAN_OPERATION_FAILED => {
+            let outcome = if event == TSEvent_TS_EVENT_CACHE_SCAN_OPERATION_BLOCKED {
+                ScanOperation::Blocked
+            } else {
+                ScanOperation::Failed
+            };
+
+            // There is no object to delete here, a delete moves on like
+            // `Continue`.
+            return match scan.visitor.operation(outcome) {
+                ScanAction::Delete => ScanAction::Continue,
+                action => action,
+            }
+            .as_raw() as c_int;
+        }
+        TSEvent_TS_EVENT_CACHE_SCAN_DONE => Ok(()),
+        TSEvent_TS_EVENT_CACHE_SCAN_FAILED => Err(CacheError::ScanFailed(edata as isize as i32)),
+        _ => return 0,
+    };
+
+    let mut state = Box::from_raw(TSContDataGet(contp) as *mut ScanState);
+    state.visitor.done(result);
+    TSContDataSet(contp, ptr::null_mut());
+    TSContDestroy(contp);
+
+    0
+}
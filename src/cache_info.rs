@@ -0,0 +1,127 @@
+use crate::bindings::*;
+use crate::header::{HttpHeader, HttpHeaderRef};
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Metadata of a cached HTTP object: the request and response it was stored
+/// with, its size and timestamps.
+///
+/// Infos created with `new` or cloned own their data, while infos obtained
+/// from ATS are left untouched on drop.
+pub struct CacheHttpInfo {
+    info: TSCacheHttpInfo,
+    owned: bool,
+}
+
+// SAFETY: borrowed infos are only read while ATS keeps the object alive,
+// owned ones are plain data.
+unsafe impl Send for CacheHttpInfo {}
+
+impl CacheHttpInfo {
+    pub fn new() -> CacheHttpInfo {
+        CacheHttpInfo { info: unsafe { TSCacheHttpInfoCreate() }, owned: true }
+    }
+
+    /// Wraps an info owned by ATS, e.g. one passed to a cache scan.
+    ///
+    /// # Safety
+    ///
+    /// `info` must be valid for as long as the wrapper and the headers read
+    /// from it are used.
+    pub unsafe fn from_raw(info: TSCacheHttpInfo) -> CacheHttpInfo {
+        CacheHttpInfo { info, owned: false }
+    }
+
+    pub fn as_raw(&self) -> TSCacheHttpInfo {
+        self.info
+    }
+
+    /// The request the object was cached for.
+    pub fn request(&self) -> Option<HttpHeaderRef<'_>> {
+        let mut bufp: TSMBuffer = ptr::null_mut();
+        let mut hdrp: TSMLoc = ptr::null_mut();
+
+        unsafe {
+            TSCacheHttpInfoReqGet(self.info, &mut bufp, &mut hdrp);
+            if bufp.is_null() || hdrp.is_null() {
+                return None;
+            }
+            Some(HttpHeaderRef::from_raw(bufp, hdrp))
+        }
+    }
+
+    /// The cached response.
+    pub fn response(&self) -> Option<HttpHeaderRef<'_>> {
+        let mut bufp: TSMBuffer = ptr::null_mut();
+        let mut hdrp: TSMLoc = ptr::null_mut();
+
+        unsafe {
+            TSCacheHttpInfoRespGet(self.info, &mut bufp, &mut hdrp);
+            if bufp.is_null() || hdrp.is_null() {
+                return None;
+            }
+            Some(HttpHeaderRef::from_raw(bufp, hdrp))
+        }
+    }
+
+    /// Stores a copy of `request` as the cached request.
+    pub fn set_request(&self, request: &HttpHeader) {
+        let (bufp, hdrp) = request.as_raw();
+        unsafe { TSCacheHttpInfoReqSet(self.info, bufp, hdrp) }
+    }
+
+    /// Stores a copy of `response` as the cached response.
+    pub fn set_response(&self, response: &HttpHeader) {
+        let (bufp, hdrp) = response.as_raw();
+        unsafe { TSCacheHttpInfoRespSet(self.info, bufp, hdrp) }
+    }
+
+    /// The URL of the cached request.
+    pub fn url(&self) -> Option<String> {
+        self.request()?.url()
+    }
+
+    /// The size of the cached body in bytes.
+    pub fn size(&self) -> i64 {
+        unsafe { TSCacheHttpInfoSizeGet(self.info) }
+    }
+
+    pub fn set_size(&self, size: i64) {
+        unsafe { TSCacheHttpInfoSizeSet(self.info, size) }
+    }
+
+    /// When the request was sent to the origin server.
+    pub fn request_sent_time(&self) -> SystemTime {
+        system_time(unsafe { TSCacheHttpInfoReqSentTimeGet(self.info) })
+    }
+
+    /// When the response was received from the origin server.
+    pub fn response_received_time(&self) -> SystemTime {
+        system_time(unsafe { TSCacheHttpInfoRespReceivedTimeGet(self.info) })
+    }
+}
+
+impl Default for CacheHttpInfo {
+    fn default() -> CacheHttpInfo {
+        CacheHttpInfo::new()
+    }
+}
+
+/// Cloning copies the info, the clone owns its data.
+impl Clone for CacheHttpInfo {
+    fn clone(&self) -> CacheHttpInfo {
+        CacheHttpInfo { info: unsafe { TSCacheHttpInfoCopy(self.info) }, owned: true }
+    }
+}
+
+impl Drop for CacheHttpInfo {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { TSCacheHttpInfoDestroy(self.info) }
+        }
+    }
+}
+
+pub(crate) fn system_time(secs: time_t) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
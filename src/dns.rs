@@ -0,0 +1,121 @@
+use crate::addr::socket_addr;
+use crate::bindings::*;
+use crate::executor::lock;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::raw::{c_int, c_void};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Resolves `name` through the ATS host database without blocking.
+///
+/// The resolved address has port 0. Dropping the future cancels the lookup.
+pub fn host_lookup(name: &str) -> HostLookup {
+    let state = Arc::new(Mutex::new(LookupState {
+        action: ptr::null_mut(),
+        result: None,
+        waker: None,
+    }));
+
+    unsafe {
+        let mutex = TSMutexCreate();
+        let contp = TSContCreate(Some(handle_lookup_event), mutex);
+        TSContDataSet(contp, Arc::into_raw(state.clone()) as *mut c_void);
+
+        // Cached names complete synchronously, so only the ATS lock is held
+        // while the lookup starts.
+        TSMutexLock(mutex);
+        let action = TSHostLookup(contp, name.as_ptr() as *const _, name.len());
+        {
+            let mut state = lock(&state);
+            if state.result.is_none() && TSActionDone(action) == 0 {
+                state.action = action;
+            }
+        }
+        TSMutexUnlock(mutex);
+
+        HostLookup { contp, mutex, state, name: name.to_string() }
+    }
+}
+
+/// A pending `host_lookup`.
+pub struct HostLookup {
+    contp: TSCont,
+    mutex: TSMutex,
+    state: Arc<Mutex<LookupState>>,
+    name: String,
+}
+
+// SAFETY: the continuation is only touched while holding its mutex.
+unsafe impl Send for HostLookup {}
+
+struct LookupState {
+    action: TSAction,
+    result: Option<Option<SocketAddr>>,
+    waker: Option<Waker>,
+}
+
+// SAFETY: see `HostLookup`.
+unsafe impl Send for LookupState {}
+
+impl Future for HostLookup {
+    type Output = io::Result<SocketAddr>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<SocketAddr>> {
+        let mut state = lock(&self.state);
+        match state.result {
+            Some(Some(addr)) => Poll::Ready(Ok(addr)),
+            Some(None) => {
+                let message = format!("failed to resolve {}", self.name);
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::NotFound, message)))
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for HostLookup {
+    fn drop(&mut self) {
+        unsafe {
+            TSMutexLock(self.mutex);
+            {
+                let mut state = lock(&self.state);
+                if !state.action.is_null() {
+                    TSActionCancel(state.action);
+                    state.action = ptr::null_mut();
+                }
+            }
+            TSMutexUnlock(self.mutex);
+
+            drop(Arc::from_raw(TSContDataGet(self.contp) as *const Mutex<LookupState>));
+            TSContDataSet(self.contp, ptr::null_mut());
+            TSContDestroy(self.contp);
+        }
+    }
+}
+
+unsafe extern "C" fn handle_lookup_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    let ptr = TSContDataGet(contp) as *const Mutex<LookupState>;
+    if ptr.is_null() || event != TSEvent_TS_EVENT_HOST_LOOKUP {
+        return 0;
+    }
+
+    // The result is only valid during the callback, copy the address out.
+    let result = edata as TSHostLookupResult;
+    let addr = if result.is_null() { None } else { socket_addr(TSHostLookupResultAddrGet(result)) };
+
+    let mut state = lock(&*ptr);
+    state.action = ptr::null_mut();
+    state.result = Some(addr);
+    if let Some(w) = state.waker.take() {
+        w.wake();
+    }
+
+    0
+}
@@ -0,0 +1,220 @@
+use crate::bindings::*;
+use crate::txn::Transaction;
+use std::future::Future;
+use std::os::raw::{c_int, c_void};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs `future` to completion on the ATS net threads.
+///
+/// The future is polled from its own continuation, and waking it schedules
+/// that continuation again with `TSContSchedule`, on any net thread. Code
+/// that touches a transaction after an await should use `Transaction::spawn`
+/// instead, so that it runs on the thread of the transaction.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    spawn_task(None, future)
+}
+
+impl Transaction {
+    /// Runs `future` to completion on the thread of the transaction.
+    ///
+    /// Every poll is scheduled with `TSHttpSchedule`, so code after an await
+    /// runs on the same thread as the transaction's state machine. Spawn it
+    /// from a hook and reenable the transaction as the last step of the
+    /// future: the transaction must stay paused until the future completes.
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        spawn_task(Some(*self), future)
+    }
+}
+
+fn spawn_task<F>(txn: Option<Transaction>, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    unsafe {
+        let contp = TSContCreate(Some(handle_task_event), TSMutexCreate());
+        let task = Arc::new(Task {
+            contp,
+            txn,
+            future: Mutex::new(Some(Box::pin(future))),
+            state: Mutex::new(TaskState::default()),
+        });
+
+        TSContDataSet(contp, Arc::into_raw(task.clone()) as *mut c_void);
+        task.schedule();
+    }
+}
+
+struct Task {
+    contp: TSCont,
+    txn: Option<Transaction>,
+    future: Mutex<Option<BoxFuture>>,
+    state: Mutex<TaskState>,
+}
+
+// SAFETY: the continuation is only scheduled while the task is alive, and
+// ATS serializes its calls with the continuation mutex.
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
+
+#[derive(Default)]
+struct TaskState {
+    scheduled: bool,
+    done: bool,
+}
+
+impl Task {
+    fn schedule(&self) {
+        let mut state = lock(&self.state);
+        if state.scheduled || state.done {
+            return;
+        }
+
+        state.scheduled = true;
+        unsafe {
+            match self.txn {
+                Some(txn) => TSHttpSchedule(self.contp, txn.as_raw(), 0),
+                None => TSContSchedule(self.contp, 0, TSThreadPool_TS_THREAD_POOL_NET),
+            };
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+unsafe extern "C" fn handle_task_event(contp: TSCont, _event: TSEvent, _edata: *mut c_void) -> c_int {
+    let ptr = TSContDataGet(contp) as *const Task;
+    Arc::increment_strong_count(ptr);
+    let task = Arc::from_raw(ptr);
+
+    {
+        let mut state = lock(&task.state);
+        state.scheduled = false;
+        if state.done {
+            drop(state);
+            destroy_task(contp, ptr);
+            return 0;
+        }
+    }
+
+    let waker = Waker::from(task.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let finished = match lock(&task.future).as_mut() {
+        Some(future) => future.as_mut().poll(&mut cx).is_ready(),
+        None => true,
+    };
+
+    if finished {
+        lock(&task.future).take();
+
+        let mut state = lock(&task.state);
+        state.done = true;
+        // A pending schedule still targets this continuation, it will be
+        // destroyed when that event comes in.
+        if !state.scheduled {
+            drop(state);
+            destroy_task(contp, ptr);
+        }
+    }
+
+    0
+}
+
+unsafe fn destroy_task(contp: TSCont, ptr: *const Task) {
+    drop(Arc::from_raw(ptr));
+    TSContDataSet(contp, std::ptr::null_mut());
+    TSContDestroy(contp);
+}
+
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Creates a single-value channel to turn an ATS callback into a future.
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let shared = Arc::new(Mutex::new(Oneshot {
+        value: None,
+        waker: None,
+        closed: false,
+    }));
+
+    (OneshotSender { shared: shared.clone() }, OneshotReceiver { shared })
+}
+
+struct Oneshot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+pub struct OneshotSender<T> {
+    shared: Arc<Mutex<Oneshot<T>>>,
+}
+
+impl<T> OneshotSender<T> {
+    pub fn send(self, value: T) {
+        let waker = {
+            let mut shared = lock(&self.shared);
+            shared.value = Some(value);
+            shared.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut shared = lock(&self.shared);
+            shared.closed = true;
+            shared.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves to the sent value, or `None` if the sender was dropped first.
+pub struct OneshotReceiver<T> {
+    shared: Arc<Mutex<Oneshot<T>>>,
+}
+
+impl<T> Future for OneshotReceiver<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut shared = lock(&self.shared);
+        if let Some(value) = shared.value.take() {
+            return Poll::Ready(Some(value));
+        }
+        if shared.closed {
+            return Poll::Ready(None);
+        }
+
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -0,0 +1,493 @@
+use crate::addr::RawSockAddr;
+use crate::bindings::*;
+use crate::executor::oneshot;
+use crate::header::{is_valid_field_name, is_valid_field_value, HeaderParser, HttpHeader};
+use crate::intercept::ChunkedDecoder;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::raw::{c_char, c_int, c_void};
+use std::slice;
+use std::time::Duration;
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    /// The request could not be created, e.g. the url contains a NUL byte.
+    Invalid(String),
+    /// ATS reported an error while talking to the server.
+    Failed,
+    /// No complete response arrived within the configured timeout.
+    Timeout,
+    /// The fetch was dropped before completing.
+    Cancelled,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Invalid(msg) => write!(f, "invalid fetch request: {}", msg),
+            FetchError::Failed => write!(f, "fetch failed"),
+            FetchError::Timeout => write!(f, "fetch timed out"),
+            FetchError::Cancelled => write!(f, "fetch cancelled"),
+        }
+    }
+}
+
+impl Error for FetchError {}
+
+/// Receives a streamed fetch response.
+pub trait FetchHandler: Send {
+    /// Called once the response header has been read.
+    fn head(&mut self, _header: &HttpHeader) {}
+
+    /// Called with each chunk of the de-chunked response body.
+    fn body(&mut self, _chunk: &[u8]) {}
+
+    /// Called exactly once, when the fetch completes or fails.
+    fn done(&mut self, result: Result<(), FetchError>);
+}
+
+/// A fully buffered fetch response.
+pub struct FetchResponse {
+    pub header: HttpHeader,
+    pub body: Vec<u8>,
+}
+
+impl FetchResponse {
+    pub fn status(&self) -> u16 {
+        self.header.status()
+    }
+}
+
+/// An outbound HTTP request issued through the ATS `TSFetch*` API.
+pub struct HttpFetch {
+    method: String,
+    url: String,
+    version: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    client_addr: SocketAddr,
+    timeout: Option<Duration>,
+}
+
+impl HttpFetch {
+    pub fn new(method: &str, url: &str) -> HttpFetch {
+        HttpFetch {
+            method: method.to_string(),
+            url: url.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            client_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            timeout: None,
+        }
+    }
+
+    pub fn get(url: &str) -> HttpFetch {
+        HttpFetch::new("GET", url)
+    }
+
+    pub fn post(url: &str) -> HttpFetch {
+        HttpFetch::new("POST", url)
+    }
+
+    pub fn version(mut self, version: &str) -> HttpFetch {
+        self.version = version.to_string();
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> HttpFetch {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the request body, `Content-Length` is added unless already set.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> HttpFetch {
+        self.body = body.into();
+        self
+    }
+
+    /// The address ATS reports as the client of the request, localhost by default.
+    pub fn client_addr(mut self, addr: SocketAddr) -> HttpFetch {
+        self.client_addr = addr;
+        self
+    }
+
+    /// Fails the fetch with `FetchError::Timeout` if it has not completed in time.
+    pub fn timeout(mut self, timeout: Duration) -> HttpFetch {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Launches the request, streaming the response to `handler`.
+    pub fn stream<H: FetchHandler + 'static>(self, handler: H) {
+        self.launch(Box::new(handler))
+    }
+
+    /// Launches the request and buffers the whole response for `f`.
+    pub fn on_complete<F>(self, f: F)
+    where
+        F: FnOnce(Result<FetchResponse, FetchError>) + Send + 'static,
+    {
+        self.stream(Collect {
+            header: None,
+            body: Vec::new(),
+            callback: Some(Box::new(f)),
+        })
+    }
+
+    /// Launches the request and resolves to the buffered response.
+    pub async fn send(self) -> Result<FetchResponse, FetchError> {
+        let (tx, rx) = oneshot();
+        self.on_complete(move |result| tx.send(result));
+        rx.await.unwrap_or(Err(FetchError::Cancelled))
+    }
+
+    /// Checks the parts ATS copies verbatim into the request line and
+    /// headers, so that none of them can end it early.
+    fn validate(&self) -> Result<(), FetchError> {
+        let is_valid_line_part = |s: &str| {
+            !s.is_empty() && !s.bytes().any(|b| b == b'\r' || b == b'\n' || b == b' ' || b == 0)
+        };
+        if !is_valid_field_name(&self.method) {
+            return Err(FetchError::Invalid(format!("invalid method: {:?}", self.method)));
+        }
+        if !is_valid_line_part(&self.url) {
+            return Err(FetchError::Invalid(format!("invalid url: {:?}", self.url)));
+        }
+        if !is_valid_line_part(&self.version) {
+            return Err(FetchError::Invalid(format!("invalid version: {:?}", self.version)));
+        }
+        for (name, value) in &self.headers {
+            if !is_valid_field_name(name) {
+                return Err(FetchError::Invalid(format!("invalid header name: {:?}", name)));
+            }
+            if !is_valid_field_value(value) {
+                return Err(FetchError::Invalid(format!("invalid value for header {}", name)));
+            }
+        }
+        Ok(())
+    }
+
+    fn launch(self, mut handler: Box<dyn FetchHandler>) {
+        if let Err(err) = self.validate() {
+            return handler.done(Err(err));
+        }
+        let cstr = |s: &str| CString::new(s).map_err(|_e| FetchError::Invalid(format!("NUL byte in {:?}", s)));
+        let (method, url, version) = match (cstr(&self.method), cstr(&self.url), cstr(&self.version)) {
+            (Ok(m), Ok(u), Ok(v)) => (m, u, v),
+            (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return handler.done(Err(err)),
+        };
+        let addr = RawSockAddr::new(&self.client_addr);
+
+        let has_length = self.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("content-length"));
+        let flags = (TSFetchFlags_TS_FETCH_FLAGS_STREAM | TSFetchFlags_TS_FETCH_FLAGS_DECHUNK) as c_int;
+
+        unsafe {
+            let mutex = TSMutexCreate();
+            let contp = TSContCreate(Some(handle_fetch_event), mutex);
+            let fetch = TSFetchCreate(contp, method.as_ptr(), url.as_ptr(), version.as_ptr(), addr.as_ptr(), flags);
+            if fetch.is_null() {
+                TSContDestroy(contp);
+                return handler.done(Err(FetchError::Invalid(format!("unable to fetch {}", self.url))));
+            }
+
+            for (name, value) in &self.headers {
+                fetch_header_add(fetch, name, value);
+            }
+            if !self.body.is_empty() && !has_length {
+                fetch_header_add(fetch, "Content-Length", &self.body.len().to_string());
+            }
+
+            let state = Box::new(FetchState {
+                fetch,
+                handler,
+                timeout: std::ptr::null_mut(),
+            });
+
+            // Events are delivered under the continuation lock, hold it until
+            // the state is complete.
+            TSMutexLock(mutex);
+            let state = Box::into_raw(state);
+            TSContDataSet(contp, state as *mut c_void);
+            TSFetchLaunch(fetch);
+            if !self.body.is_empty() {
+                TSFetchWriteData(fetch, self.body.as_ptr() as *const c_void, self.body.len());
+            }
+            if let Some(timeout) = self.timeout {
+                let ms = timeout.as_millis().min(TSHRTime::MAX as u128) as TSHRTime;
+                (*state).timeout = TSContSchedule(contp, ms, TSThreadPool_TS_THREAD_POOL_NET);
+            }
+            TSMutexUnlock(mutex);
+        }
+    }
+}
+
+unsafe fn fetch_header_add(fetch: TSFetchSM, name: &str, value: &str) {
+    TSFetchHeaderAdd(
+        fetch,
+        name.as_ptr() as *const c_char,
+        name.len() as c_int,
+        value.as_ptr() as *const c_char,
+        value.len() as c_int,
+    );
+}
+
+type Callback = Box<dyn FnOnce(Result<FetchResponse, FetchError>) + Send>;
+
+struct Collect {
+    header: Option<HttpHeader>,
+    body: Vec<u8>,
+    callback: Option<Callback>,
+}
+
+impl FetchHandler for Collect {
+    fn head(&mut self, header: &HttpHeader) {
+        self.header = Some(header.clone());
+    }
+
+    fn body(&mut self, chunk: &[u8]) {
+        self.body.extend_from_slice(chunk);
+    }
+
+    fn done(&mut self, result: Result<(), FetchError>) {
+        let callback = match self.callback.take() {
+            Some(cb) => cb,
+            None => return,
+        };
+
+        let response = result.and_then(|_| match self.header.take() {
+            Some(header) => Ok(FetchResponse {
+                header,
+                body: std::mem::take(&mut self.body),
+            }),
+            None => Err(FetchError::Failed),
+        });
+        callback(response);
+    }
+}
+
+struct FetchState {
+    fetch: TSFetchSM,
+    handler: Box<dyn FetchHandler>,
+    timeout: TSAction,
+}
+
+impl FetchState {
+    unsafe fn read_body(&mut self) {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = TSFetchReadData(self.fetch, buf.as_mut_ptr() as *mut c_void, buf.len());
+            if n <= 0 {
+                break;
+            }
+            self.handler.body(&buf[..n as usize]);
+        }
+    }
+}
+
+unsafe fn finish_fetch(contp: TSCont, state: *mut FetchState, result: Result<(), FetchError>, timed_out: bool) {
+    let mut state = Box::from_raw(state);
+    if !timed_out && !state.timeout.is_null() {
+        TSActionCancel(state.timeout);
+    }
+
+    TSFetchDestroy(state.fetch);
+    state.handler.done(result);
+
+    TSContDataSet(contp, std::ptr::null_mut());
+    TSContDestroy(contp);
+}
+
+unsafe extern "C" fn handle_fetch_event(contp: TSCont, event: TSEvent, _edata: *mut c_void) -> c_int {
+    let ptr = TSContDataGet(contp) as *mut FetchState;
+    if ptr.is_null() {
+        return 0;
+    }
+    let state = &mut *ptr;
+
+    match event as i32 {
+        TSFetchEventExt_TS_FETCH_EVENT_EXT_HEAD_READY => {}
+        TSFetchEventExt_TS_FETCH_EVENT_EXT_HEAD_DONE => {
+            let header = HttpHeader::copy_raw(TSFetchRespHdrMBufGet(state.fetch), TSFetchRespHdrMLocGet(state.fetch));
+            state.handler.head(&header);
+        }
+        TSFetchEventExt_TS_FETCH_EVENT_EXT_BODY_READY => state.read_body(),
+        TSFetchEventExt_TS_FETCH_EVENT_EXT_BODY_DONE => {
+            state.read_body();
+            finish_fetch(contp, ptr, Ok(()), false);
+        }
+        e if e == TSEvent_TS_EVENT_TIMEOUT as i32 => finish_fetch(contp, ptr, Err(FetchError::Timeout), true),
+        _ => finish_fetch(contp, ptr, Err(FetchError::Failed), false),
+    }
+
+    0
+}
+
+/// A request for `fetch_url`, serialized as HTTP/1.1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl FetchRequest {
+    pub fn new(method: &str, url: &str) -> FetchRequest {
+        FetchRequest {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(url: &str) -> FetchRequest {
+        FetchRequest::new("GET", url)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> FetchRequest {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> FetchRequest {
+        self.body = body.into();
+        self
+    }
+
+    /// Serializes the request, adding `Host` from the url and
+    /// `Content-Length` from the body when they are missing. Methods and
+    /// header fields that could break the request framing are rejected.
+    fn to_bytes(&self) -> Result<Vec<u8>, FetchError> {
+        let url = Url::parse(&self.url).map_err(|e| FetchError::Invalid(e.to_string()))?;
+        if !is_valid_field_name(&self.method) {
+            return Err(FetchError::Invalid(format!("invalid method: {:?}", self.method)));
+        }
+        for (name, value) in &self.headers {
+            if !is_valid_field_name(name) {
+                return Err(FetchError::Invalid(format!("invalid header name: {:?}", name)));
+            }
+            if !is_valid_field_value(value) {
+                return Err(FetchError::Invalid(format!("invalid value for header {}", name)));
+            }
+        }
+        let has = |name: &str| self.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+
+        let mut out = format!("{} {} HTTP/1.1\r\n", self.method, url);
+        if !has("host") {
+            let host = url.host_str().ok_or_else(|| FetchError::Invalid(format!("missing host in {}", url)))?;
+            match url.port() {
+                Some(port) => out.push_str(&format!("Host: {}:{}\r\n", host, port)),
+                None => out.push_str(&format!("Host: {}\r\n", host)),
+            }
+        }
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !self.body.is_empty() && !has("content-length") {
+            out.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        out.push_str("\r\n");
+
+        let mut bytes = out.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        Ok(bytes)
+    }
+}
+
+// Private event ids handed to `TSFetchUrl`, they only reach our continuation.
+const FETCH_URL_SUCCESS: c_int = 70000;
+const FETCH_URL_FAILURE: c_int = 70001;
+const FETCH_URL_TIMEOUT: c_int = 70002;
+
+/// Issues `request` with `TSFetchUrl` as if it came from `from_addr`, and
+/// calls `handler` with the parsed response once the body is complete.
+pub fn fetch_url<F>(request: FetchRequest, from_addr: SocketAddr, handler: F)
+where
+    F: FnOnce(Result<FetchResponse, FetchError>) + Send + 'static,
+{
+    let bytes = match request.to_bytes() {
+        Ok(b) => b,
+        Err(err) => return handler(Err(err)),
+    };
+    let addr = RawSockAddr::new(&from_addr);
+    let callback: Box<Callback> = Box::new(Box::new(handler));
+
+    unsafe {
+        let contp = TSContCreate(Some(handle_fetch_url_event), TSMutexCreate());
+        TSContDataSet(contp, Box::into_raw(callback) as *mut c_void);
+        TSFetchUrl(
+            bytes.as_ptr() as *const c_char,
+            bytes.len() as c_int,
+            addr.as_ptr(),
+            contp,
+            TSFetchWakeUpOptions_AFTER_BODY,
+            TSFetchEvent {
+                success_event_id: FETCH_URL_SUCCESS,
+                failure_event_id: FETCH_URL_FAILURE,
+                timeout_event_id: FETCH_URL_TIMEOUT,
+            },
+        );
+    }
+}
+
+unsafe fn parse_fetch_response(fetch: TSHttpTxn) -> Result<FetchResponse, FetchError> {
+    let mut len: c_int = 0;
+    let data = TSFetchRespGet(fetch, &mut len);
+    if data.is_null() || len <= 0 {
+        return Err(FetchError::Failed);
+    }
+
+    let data = slice::from_raw_parts(data as *const u8, len as usize);
+    let (header, consumed) = match HeaderParser::response().parse(data) {
+        Ok(Some(parsed)) => parsed,
+        _ => return Err(FetchError::Failed),
+    };
+
+    // ATS hands back the body as the origin sent it, so undo chunked framing
+    // to keep `body` the plain payload.
+    let chunked = header
+        .field_values("Transfer-Encoding")
+        .iter()
+        .any(|v| v.trim().eq_ignore_ascii_case("chunked"));
+    if !chunked {
+        return Ok(FetchResponse {
+            header,
+            body: data[consumed..].to_vec(),
+        });
+    }
+
+    let mut body = Vec::new();
+    match ChunkedDecoder::default().decode(&data[consumed..], &mut body) {
+        Ok(true) => {
+            header.remove_field("Transfer-Encoding");
+            header.set_field("Content-Length", &body.len().to_string());
+            Ok(FetchResponse { header, body })
+        }
+        _ => Err(FetchError::Failed),
+    }
+}
+
+unsafe extern "C" fn handle_fetch_url_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    let ptr = TSContDataGet(contp) as *mut Callback;
+    if ptr.is_null() {
+        return 0;
+    }
+
+    let result = match event as c_int {
+        FETCH_URL_SUCCESS => parse_fetch_response(edata as TSHttpTxn),
+        FETCH_URL_TIMEOUT => Err(FetchError::Timeout),
+        _ => Err(FetchError::Failed),
+    };
+
+    let callback = Box::from_raw(ptr);
+    TSContDataSet(contp, std::ptr::null_mut());
+    TSContDestroy(contp);
+    callback(result);
+
+    0
+}
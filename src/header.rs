@@ -0,0 +1,430 @@
+use crate::bindings::*;
+use crate::remap::TSHeaders;
+use crate::txn::Transaction;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice;
+
+extern "C" {
+    // `TSfree` is a macro in ts.h, bindgen only sees the function behind it.
+    pub(crate) fn _TSfree(ptr: *mut c_void);
+}
+
+pub(crate) unsafe fn ts_string(ptr: *const c_char, len: c_int) -> Option<String> {
+    if ptr.is_null() || len < 0 {
+        return None;
+    }
+
+    let bytes = slice::from_raw_parts(ptr as *const u8, len as usize);
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Returns a string allocated by ATS, releasing its memory afterwards.
+pub(crate) unsafe fn ts_owned_string(ptr: *mut c_char, len: c_int) -> Option<String> {
+    let s = ts_string(ptr, len);
+    if !ptr.is_null() {
+        _TSfree(ptr as *mut c_void);
+    }
+    s
+}
+
+/// Whether `name` is a valid header field name, an RFC 7230 token.
+pub(crate) fn is_valid_field_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Whether `value` can be written as a header field value, without bytes
+/// that would end the field early.
+pub(crate) fn is_valid_field_value(value: &str) -> bool {
+    !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+}
+
+/// An HTTP request or response header stored in a `TSMBuffer`.
+///
+/// Headers created with `new_request`/`new_response` own their buffer, while
+/// headers obtained from a transaction only release their handle on drop.
+pub struct HttpHeader {
+    bufp: TSMBuffer,
+    hdrp: TSMLoc,
+    owned: bool,
+}
+
+// SAFETY: marshal buffers are not tied to the thread that created them.
+unsafe impl Send for HttpHeader {}
+
+impl HttpHeader {
+    pub fn new_request() -> HttpHeader {
+        HttpHeader::create(TSHttpType_TS_HTTP_TYPE_REQUEST)
+    }
+
+    pub fn new_response() -> HttpHeader {
+        HttpHeader::create(TSHttpType_TS_HTTP_TYPE_RESPONSE)
+    }
+
+    fn create(kind: TSHttpType) -> HttpHeader {
+        unsafe {
+            let bufp = TSMBufferCreate();
+            let hdrp = TSHttpHdrCreate(bufp);
+            TSHttpHdrTypeSet(bufp, hdrp, kind);
+            HttpHeader { bufp, hdrp, owned: true }
+        }
+    }
+
+    /// Wraps a header owned by ATS, e.g. one returned by `TSHttpTxnClientReqGet`.
+    ///
+    /// # Safety
+    ///
+    /// `bufp` and `hdrp` must be a valid header handle, which is released on drop.
+    pub unsafe fn from_raw(bufp: TSMBuffer, hdrp: TSMLoc) -> HttpHeader {
+        HttpHeader { bufp, hdrp, owned: false }
+    }
+
+    /// Copies a header owned by ATS into a new buffer, leaving the original untouched.
+    pub(crate) unsafe fn copy_raw(bufp: TSMBuffer, hdrp: TSMLoc) -> HttpHeader {
+        let dest = TSMBufferCreate();
+        let mut loc: TSMLoc = ptr::null_mut();
+        TSHttpHdrClone(dest, bufp, hdrp, &mut loc);
+        HttpHeader { bufp: dest, hdrp: loc, owned: true }
+    }
+
+    pub fn as_raw(&self) -> (TSMBuffer, TSMLoc) {
+        (self.bufp, self.hdrp)
+    }
+
+    pub fn method(&self) -> Option<String> {
+        let mut len: c_int = 0;
+        unsafe { ts_string(TSHttpHdrMethodGet(self.bufp, self.hdrp, &mut len), len) }
+    }
+
+    pub fn set_method(&self, method: &str) {
+        unsafe {
+            TSHttpHdrMethodSet(self.bufp, self.hdrp, method.as_ptr() as *const c_char, method.len() as c_int);
+        }
+    }
+
+    pub fn url(&self) -> Option<String> {
+        unsafe {
+            let mut url: TSMLoc = ptr::null_mut();
+            if TSHttpHdrUrlGet(self.bufp, self.hdrp, &mut url) != TSReturnCode_TS_SUCCESS {
+                return None;
+            }
+
+            let mut len: c_int = 0;
+            let s = ts_owned_string(TSUrlStringGet(self.bufp, url, &mut len), len);
+            TSHandleMLocRelease(self.bufp, self.hdrp, url);
+            s
+        }
+    }
+
+    /// Parses `url` and sets it as the request target.
+    pub fn set_url(&self, url: &str) -> Result<(), String> {
+        unsafe {
+            let mut loc: TSMLoc = ptr::null_mut();
+            if TSUrlCreate(self.bufp, &mut loc) != TSReturnCode_TS_SUCCESS {
+                return Err("unable to create url".to_string());
+            }
+
+            let mut start = url.as_ptr() as *const c_char;
+            let end = start.add(url.len());
+            let parsed = TSUrlParse(self.bufp, loc, &mut start, end) == TSParseResult_TS_PARSE_DONE;
+            if parsed {
+                TSHttpHdrUrlSet(self.bufp, self.hdrp, loc);
+            }
+            TSHandleMLocRelease(self.bufp, self.hdrp, loc);
+
+            if parsed {
+                Ok(())
+            } else {
+                Err(format!("invalid url: {}", url))
+            }
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        unsafe { TSHttpHdrStatusGet(self.bufp, self.hdrp) as u16 }
+    }
+
+    pub fn set_status(&self, status: u16) {
+        unsafe {
+            TSHttpHdrStatusSet(self.bufp, self.hdrp, status as TSHttpStatus);
+        }
+    }
+
+    /// Returns the HTTP version as a `(major, minor)` pair.
+    pub fn version(&self) -> (u16, u16) {
+        let v = unsafe { TSHttpHdrVersionGet(self.bufp, self.hdrp) };
+        ((v >> 16) as u16, (v & 0xffff) as u16)
+    }
+
+    /// Returns every value of the field `name`, or an empty list if it is missing.
+    pub fn field_values(&self, name: &str) -> Vec<String> {
+        let mut values = Vec::new();
+
+        unsafe {
+            let mut field = self.find(name);
+            while !field.is_null() {
+                let count = TSMimeHdrFieldValuesCount(self.bufp, self.hdrp, field);
+                for idx in 0..count {
+                    let mut len: c_int = 0;
+                    let value = TSMimeHdrFieldValueStringGet(self.bufp, self.hdrp, field, idx, &mut len);
+                    if let Some(v) = ts_string(value, len) {
+                        values.push(v);
+                    }
+                }
+
+                let next = TSMimeHdrFieldNextDup(self.bufp, self.hdrp, field);
+                TSHandleMLocRelease(self.bufp, self.hdrp, field);
+                field = next;
+            }
+        }
+
+        values
+    }
+
+    /// Returns the first value of the field `name`.
+    pub fn field(&self, name: &str) -> Option<String> {
+        self.field_values(name).into_iter().next()
+    }
+
+    /// Replaces every occurrence of `name` with a single field set to `value`.
+    pub fn set_field(&self, name: &str, value: &str) {
+        self.remove_field(name);
+        self.append_field(name, value);
+    }
+
+    /// Adds a new field, keeping any existing field with the same name.
+    pub fn append_field(&self, name: &str, value: &str) {
+        unsafe {
+            let mut field: TSMLoc = ptr::null_mut();
+            let created = TSMimeHdrFieldCreateNamed(
+                self.bufp,
+                self.hdrp,
+                name.as_ptr() as *const c_char,
+                name.len() as c_int,
+                &mut field,
+            );
+            if created != TSReturnCode_TS_SUCCESS {
+                return;
+            }
+
+            TSMimeHdrFieldValueStringSet(
+                self.bufp,
+                self.hdrp,
+                field,
+                -1,
+                value.as_ptr() as *const c_char,
+                value.len() as c_int,
+            );
+            TSMimeHdrFieldAppend(self.bufp, self.hdrp, field);
+            TSHandleMLocRelease(self.bufp, self.hdrp, field);
+        }
+    }
+
+    pub fn remove_field(&self, name: &str) {
+        unsafe {
+            let mut field = self.find(name);
+            while !field.is_null() {
+                let next = TSMimeHdrFieldNextDup(self.bufp, self.hdrp, field);
+                TSMimeHdrFieldDestroy(self.bufp, self.hdrp, field);
+                TSHandleMLocRelease(self.bufp, self.hdrp, field);
+                field = next;
+            }
+        }
+    }
+
+    /// Collects all the fields, grouping duplicated names together.
+    pub fn headers(&self) -> TSHeaders {
+        let mut headers = TSHeaders::default();
+
+        unsafe {
+            let count = TSMimeHdrFieldsCount(self.bufp, self.hdrp);
+            for idx in 0..count {
+                let field = TSMimeHdrFieldGet(self.bufp, self.hdrp, idx);
+                if field.is_null() {
+                    continue;
+                }
+
+                let mut len: c_int = 0;
+                let name = TSMimeHdrFieldNameGet(self.bufp, self.hdrp, field, &mut len);
+                if let Some(name) = ts_string(name, len) {
+                    let values = headers.entry(name).or_insert_with(Vec::new);
+                    let count = TSMimeHdrFieldValuesCount(self.bufp, self.hdrp, field);
+                    for value_idx in 0..count {
+                        let mut len: c_int = 0;
+                        let value = TSMimeHdrFieldValueStringGet(self.bufp, self.hdrp, field, value_idx, &mut len);
+                        if let Some(v) = ts_string(value, len) {
+                            values.push(v);
+                        }
+                    }
+                }
+
+                TSHandleMLocRelease(self.bufp, self.hdrp, field);
+            }
+        }
+
+        headers
+    }
+
+    unsafe fn find(&self, name: &str) -> TSMLoc {
+        TSMimeHdrFieldFind(self.bufp, self.hdrp, name.as_ptr() as *const c_char, name.len() as c_int)
+    }
+}
+
+/// Cloning copies the header into a new buffer owned by the clone.
+impl Clone for HttpHeader {
+    fn clone(&self) -> HttpHeader {
+        unsafe { HttpHeader::copy_raw(self.bufp, self.hdrp) }
+    }
+}
+
+impl Drop for HttpHeader {
+    fn drop(&mut self) {
+        unsafe {
+            if self.owned {
+                TSHttpHdrDestroy(self.bufp, self.hdrp);
+                TSHandleMLocRelease(self.bufp, ptr::null_mut(), self.hdrp);
+                TSMBufferDestroy(self.bufp);
+            } else {
+                TSHandleMLocRelease(self.bufp, ptr::null_mut(), self.hdrp);
+            }
+        }
+    }
+}
+
+/// A header read from an object that owns it, e.g. a cached info, usable
+/// only while that object is borrowed. `clone` gives an owned copy.
+pub struct HttpHeaderRef<'a> {
+    header: HttpHeader,
+    owner: PhantomData<&'a ()>,
+}
+
+impl<'a> HttpHeaderRef<'a> {
+    /// Wraps a header handle owned by the object borrowed for `'a`.
+    ///
+    /// # Safety
+    ///
+    /// `bufp` and `hdrp` must be a valid header handle for all of `'a`.
+    pub unsafe fn from_raw(bufp: TSMBuffer, hdrp: TSMLoc) -> HttpHeaderRef<'a> {
+        HttpHeaderRef {
+            header: HttpHeader::from_raw(bufp, hdrp),
+            owner: PhantomData,
+        }
+    }
+}
+
+impl Deref for HttpHeaderRef<'_> {
+    type Target = HttpHeader;
+
+    fn deref(&self) -> &HttpHeader {
+        &self.header
+    }
+}
+
+type HeaderGetter = unsafe extern "C" fn(TSHttpTxn, *mut TSMBuffer, *mut TSMLoc) -> TSReturnCode;
+
+impl Transaction {
+    /// The request as received from the client.
+    pub fn client_request(&self) -> Option<HttpHeader> {
+        self.header(TSHttpTxnClientReqGet)
+    }
+
+    /// The response that will be sent to the client.
+    pub fn client_response(&self) -> Option<HttpHeader> {
+        self.header(TSHttpTxnClientRespGet)
+    }
+
+    /// The request sent to the origin server.
+    pub fn server_request(&self) -> Option<HttpHeader> {
+        self.header(TSHttpTxnServerReqGet)
+    }
+
+    /// The response as received from the origin server.
+    pub fn server_response(&self) -> Option<HttpHeader> {
+        self.header(TSHttpTxnServerRespGet)
+    }
+
+    /// The cached request, on a cache hit.
+    pub fn cached_request(&self) -> Option<HttpHeader> {
+        self.header(TSHttpTxnCachedReqGet)
+    }
+
+    /// The cached response, on a cache hit. Changes are not written back.
+    pub fn cached_response(&self) -> Option<HttpHeader> {
+        self.header(TSHttpTxnCachedRespGet)
+    }
+
+    /// A writable copy of the cached response, on a cache hit. Changes are
+    /// stored with `update_cached_object`.
+    pub fn cached_response_modifiable(&self) -> Option<HttpHeader> {
+        self.header(TSHttpTxnCachedRespModifiableGet)
+    }
+
+    fn header(&self, get: HeaderGetter) -> Option<HttpHeader> {
+        let mut bufp: TSMBuffer = ptr::null_mut();
+        let mut hdrp: TSMLoc = ptr::null_mut();
+
+        unsafe {
+            if get(self.as_raw(), &mut bufp, &mut hdrp) != TSReturnCode_TS_SUCCESS {
+                return None;
+            }
+            Some(HttpHeader::from_raw(bufp, hdrp))
+        }
+    }
+}
+
+/// Incrementally parses raw bytes into an owned `HttpHeader`.
+pub(crate) struct HeaderParser {
+    parser: TSHttpParser,
+    header: Option<HttpHeader>,
+}
+
+impl HeaderParser {
+    pub(crate) fn request() -> HeaderParser {
+        HeaderParser::new(HttpHeader::new_request())
+    }
+
+    pub(crate) fn response() -> HeaderParser {
+        HeaderParser::new(HttpHeader::new_response())
+    }
+
+    fn new(header: HttpHeader) -> HeaderParser {
+        HeaderParser {
+            parser: unsafe { TSHttpParserCreate() },
+            header: Some(header),
+        }
+    }
+
+    /// Feeds `data` to the parser. Once the header is complete, it returns the
+    /// header together with the number of bytes of `data` that belong to it.
+    pub(crate) fn parse(&mut self, data: &[u8]) -> Result<Option<(HttpHeader, usize)>, String> {
+        let (bufp, hdrp, is_request) = match self.header.as_ref() {
+            Some(h) => (h.bufp, h.hdrp, unsafe { TSHttpHdrTypeGet(h.bufp, h.hdrp) } == TSHttpType_TS_HTTP_TYPE_REQUEST),
+            None => return Err("header already parsed".to_string()),
+        };
+
+        let result = unsafe {
+            let mut start = data.as_ptr() as *const c_char;
+            let end = start.add(data.len());
+            let result = if is_request {
+                TSHttpHdrParseReq(self.parser, bufp, hdrp, &mut start, end)
+            } else {
+                TSHttpHdrParseResp(self.parser, bufp, hdrp, &mut start, end)
+            };
+            (result, start as usize - data.as_ptr() as usize)
+        };
+
+        match result {
+            (TSParseResult_TS_PARSE_DONE, consumed) => Ok(self.header.take().map(|h| (h, consumed))),
+            (TSParseResult_TS_PARSE_CONT, _) => Ok(None),
+            _ => Err("invalid http header".to_string()),
+        }
+    }
+}
+
+impl Drop for HeaderParser {
+    fn drop(&mut self) {
+        unsafe { TSHttpParserDestroy(self.parser) }
+    }
+}
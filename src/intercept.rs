@@ -0,0 +1,410 @@
+use crate::bindings::*;
+use crate::buffer::{IOBuffer, IOBufferReader};
+use crate::header::{is_valid_field_name, is_valid_field_value, HeaderParser, HttpHeader};
+use crate::txn::Transaction;
+use crate::vconn::{VConn, Vio};
+use std::ffi::CStr;
+use std::os::raw::{c_int, c_void};
+
+/// Serves a transaction from the plugin instead of ATS.
+pub trait Intercept: Send {
+    /// Called with each chunk of the request body, already de-chunked.
+    fn body(&mut self, _request: &HttpHeader, _chunk: &[u8]) {}
+
+    /// Called once the whole request has been read.
+    fn respond(&mut self, request: &HttpHeader) -> Response;
+
+    /// Called when the client goes away before the response is written.
+    fn aborted(&mut self) {}
+}
+
+/// Plays the origin server for a transaction. Unlike `Intercept`, the
+/// response goes through the regular ATS cache logic.
+pub trait ServerIntercept: Send {
+    /// Called with each chunk of the proxied request body, already de-chunked.
+    fn body(&mut self, _request: &HttpHeader, _chunk: &[u8]) {}
+
+    /// Called once the whole proxied request has been read. Cache related
+    /// headers such as `Cache-Control` and `Last-Modified` are honored by ATS.
+    fn respond(&mut self, request: &HttpHeader) -> Response;
+
+    /// Called when ATS drops the origin connection before the response is written.
+    fn aborted(&mut self) {}
+}
+
+struct ServerHandler<T>(T);
+
+impl<T: ServerIntercept> Intercept for ServerHandler<T> {
+    fn body(&mut self, request: &HttpHeader, chunk: &[u8]) {
+        self.0.body(request, chunk)
+    }
+
+    fn respond(&mut self, request: &HttpHeader) -> Response {
+        self.0.respond(request)
+    }
+
+    fn aborted(&mut self) {
+        self.0.aborted()
+    }
+}
+
+/// A complete response written back by an intercept.
+///
+/// `Content-Length` and `Connection` are always set by the crate, so any
+/// framing headers added here are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Serializes the response, leaving the body out for `HEAD` requests
+    /// and for statuses that cannot carry one.
+    fn to_bytes(&self, head_only: bool) -> Vec<u8> {
+        let reason = unsafe {
+            let r = TSHttpHdrReasonLookup(self.status as TSHttpStatus);
+            if r.is_null() { "" } else { CStr::from_ptr(r).to_str().unwrap_or_default() }
+        };
+
+        let mut out = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        for (name, value) in &self.headers {
+            if is_framing_header(name) {
+                continue;
+            }
+            // CR or LF would split the response, and ATS caches what a
+            // server intercept sends.
+            if !is_valid_field_name(name) || !is_valid_field_value(value) {
+                crate::ts_error(&format!("dropping invalid response header {:?}", name));
+                continue;
+            }
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        let bodyless = self.status < 200 || self.status == 204 || self.status == 304;
+        if !bodyless {
+            out.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        // The intercept connection is closed after one response.
+        out.push_str("Connection: close\r\n");
+        out.push_str("\r\n");
+
+        let mut bytes = out.into_bytes();
+        if !head_only && !bodyless {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+}
+
+fn is_framing_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case("content-length")
+        || name.eq_ignore_ascii_case("transfer-encoding")
+        || name.eq_ignore_ascii_case("connection")
+}
+
+impl Transaction {
+    /// Answers the transaction with `handler`, the response goes straight to
+    /// the client and bypasses the cache. Call it from
+    /// `TS_HTTP_READ_REQUEST_HDR_HOOK` or the remap stage, before the cache lookup.
+    pub fn intercept<T: Intercept + 'static>(&self, handler: T) {
+        unsafe {
+            let contp = intercept_cont(Box::new(handler));
+            TSHttpTxnIntercept(contp, self.as_raw());
+        }
+    }
+
+    /// Makes `handler` the origin server of the transaction. ATS only calls
+    /// it on a cache miss or revalidation, and caches the response as usual.
+    /// Call it before the origin connection is opened, e.g. from
+    /// `TS_HTTP_READ_REQUEST_HDR_HOOK` or `TS_HTTP_CACHE_LOOKUP_COMPLETE_HOOK`.
+    pub fn server_intercept<T: ServerIntercept + 'static>(&self, handler: T) {
+        unsafe {
+            let contp = intercept_cont(Box::new(ServerHandler(handler)));
+            TSHttpTxnServerIntercept(contp, self.as_raw());
+        }
+    }
+}
+
+unsafe fn intercept_cont(handler: Box<dyn Intercept>) -> TSCont {
+    let state = Box::new(InterceptState {
+        handler,
+        vc: None,
+        request_buffer: None,
+        response_buffer: None,
+        parser: HeaderParser::request(),
+        request: None,
+        body: BodyDecoder::None,
+        responded: false,
+    });
+
+    let contp = TSContCreate(Some(handle_intercept_event), TSMutexCreate());
+    TSContDataSet(contp, Box::into_raw(state) as *mut c_void);
+    contp
+}
+
+struct InterceptState {
+    handler: Box<dyn Intercept>,
+    vc: Option<VConn>,
+    request_buffer: Option<(IOBuffer, IOBufferReader)>,
+    response_buffer: Option<IOBuffer>,
+    parser: HeaderParser,
+    request: Option<HttpHeader>,
+    body: BodyDecoder,
+    responded: bool,
+}
+
+impl InterceptState {
+    unsafe fn read(&mut self, contp: TSCont) -> Result<(), String> {
+        let data = match self.request_buffer.as_ref() {
+            Some((_, reader)) => reader.drain(),
+            None => return Ok(()),
+        };
+
+        let mut body = &data[..];
+        if self.request.is_none() {
+            match self.parser.parse(&data)? {
+                Some((request, consumed)) => {
+                    self.body = BodyDecoder::for_request(&request)?;
+                    self.request = Some(request);
+                    body = &data[consumed..];
+                }
+                None => return Ok(()),
+            }
+        }
+
+        let mut chunk = Vec::new();
+        let done = self.body.decode(body, &mut chunk)?;
+        if let Some(request) = self.request.as_ref() {
+            if !chunk.is_empty() {
+                self.handler.body(request, &chunk);
+            }
+        }
+
+        if done {
+            self.respond(contp);
+        }
+        Ok(())
+    }
+
+    unsafe fn respond(&mut self, contp: TSCont) {
+        let vc = match self.vc {
+            Some(vc) => vc,
+            None => return,
+        };
+
+        let (response, head_only) = match self.request.as_ref() {
+            Some(request) => (self.handler.respond(request), request.method().map(|m| m == "HEAD").unwrap_or(false)),
+            None => (Response::new(400), false),
+        };
+
+        self.write(contp, vc, &response.to_bytes(head_only));
+    }
+
+    unsafe fn write(&mut self, contp: TSCont, vc: VConn, data: &[u8]) {
+        self.responded = true;
+        vc.shutdown(true, false);
+
+        let buffer = IOBuffer::new();
+        let reader = buffer.reader();
+        buffer.write(data);
+        vc.write(contp, reader, data.len() as i64);
+        self.response_buffer = Some(buffer);
+    }
+}
+
+unsafe fn finish_intercept(contp: TSCont, state: *mut InterceptState, abort: bool) {
+    let mut state = Box::from_raw(state);
+    if let Some(vc) = state.vc.take() {
+        if abort {
+            vc.abort(1);
+        } else {
+            vc.close();
+        }
+    }
+
+    if !state.responded || abort {
+        state.handler.aborted();
+    }
+
+    TSContDataSet(contp, std::ptr::null_mut());
+    TSContDestroy(contp);
+}
+
+unsafe extern "C" fn handle_intercept_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    let ptr = TSContDataGet(contp) as *mut InterceptState;
+    if ptr.is_null() {
+        return 0;
+    }
+    let state = &mut *ptr;
+
+    match event {
+        TSEvent_TS_EVENT_NET_ACCEPT => {
+            let vc = VConn::from_raw(edata as TSVConn);
+            let buffer = IOBuffer::new();
+            let reader = buffer.reader();
+            vc.read(contp, &buffer, i64::MAX);
+            state.request_buffer = Some((buffer, reader));
+            state.vc = Some(vc);
+        }
+        TSEvent_TS_EVENT_NET_ACCEPT_FAILED => finish_intercept(contp, ptr, false),
+        TSEvent_TS_EVENT_VCONN_READ_READY | TSEvent_TS_EVENT_VCONN_READ_COMPLETE => {
+            if state.responded {
+                return 0;
+            }
+
+            if let Err(err) = state.read(contp) {
+                crate::ts_error(&format!("intercept: {}", err));
+                if let Some(vc) = state.vc {
+                    let response = Response::new(400).body(err);
+                    state.write(contp, vc, &response.to_bytes(false));
+                }
+            } else if event == TSEvent_TS_EVENT_VCONN_READ_READY && !state.responded {
+                Vio::from_raw(edata as TSVIO).reenable();
+            }
+        }
+        TSEvent_TS_EVENT_VCONN_WRITE_READY => Vio::from_raw(edata as TSVIO).reenable(),
+        TSEvent_TS_EVENT_VCONN_WRITE_COMPLETE => finish_intercept(contp, ptr, false),
+        // ATS signals EOS once it has nothing more to send, which only
+        // means the client is gone if the response was not written yet.
+        TSEvent_TS_EVENT_VCONN_EOS => {
+            if !state.responded {
+                finish_intercept(contp, ptr, true);
+            }
+        }
+        _ => finish_intercept(contp, ptr, true),
+    }
+
+    0
+}
+
+/// Tracks how much of a request body is left to read.
+enum BodyDecoder {
+    None,
+    Length(u64),
+    Chunked(ChunkedDecoder),
+}
+
+impl BodyDecoder {
+    fn for_request(request: &HttpHeader) -> Result<BodyDecoder, String> {
+        let chunked = request
+            .field_values("Transfer-Encoding")
+            .iter()
+            .any(|v| v.trim().eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return Ok(BodyDecoder::Chunked(ChunkedDecoder::default()));
+        }
+
+        match request.field("Content-Length") {
+            Some(len) => len
+                .trim()
+                .parse()
+                .map(BodyDecoder::Length)
+                .map_err(|_e| format!("invalid content-length: {}", len)),
+            None => Ok(BodyDecoder::None),
+        }
+    }
+
+    /// Appends the body bytes found in `input` to `out`, returning whether
+    /// the body is complete.
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<bool, String> {
+        match self {
+            BodyDecoder::None => Ok(true),
+            BodyDecoder::Length(remaining) => {
+                let len = (*remaining).min(input.len() as u64);
+                out.extend_from_slice(&input[..len as usize]);
+                *remaining -= len;
+                Ok(*remaining == 0)
+            }
+            BodyDecoder::Chunked(decoder) => decoder.decode(input, out),
+        }
+    }
+}
+
+/// Strips `Transfer-Encoding: chunked` framing, also used for `fetch_url`
+/// bodies.
+#[derive(Default)]
+pub(crate) struct ChunkedDecoder {
+    pending: Vec<u8>,
+    state: ChunkState,
+}
+
+#[derive(Default)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+impl ChunkedDecoder {
+    pub(crate) fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<bool, String> {
+        self.pending.extend_from_slice(input);
+
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let line = match self.take_line() {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size = u64::from_str_radix(size, 16).map_err(|_e| format!("invalid chunk size: {}", size))?;
+                    self.state = if size == 0 { ChunkState::Trailer } else { ChunkState::Data(size) };
+                }
+                ChunkState::Data(remaining) => {
+                    if self.pending.is_empty() {
+                        return Ok(false);
+                    }
+                    let len = remaining.min(self.pending.len() as u64);
+                    out.extend(self.pending.drain(..len as usize));
+                    self.state = if len == remaining { ChunkState::DataEnd } else { ChunkState::Data(remaining - len) };
+                }
+                ChunkState::DataEnd => {
+                    if self.pending.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &self.pending[..2] != b"\r\n" {
+                        return Err("invalid chunk terminator".to_string());
+                    }
+                    self.pending.drain(..2);
+                    self.state = ChunkState::Size;
+                }
+                ChunkState::Trailer => match self.take_line() {
+                    Some(line) if line.is_empty() => self.state = ChunkState::Done,
+                    Some(_) => {}
+                    None => return Ok(false),
+                },
+                ChunkState::Done => return Ok(true),
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> Option<String> {
+        let pos = self.pending.windows(2).position(|w| w == b"\r\n")?;
+        let line = String::from_utf8_lossy(&self.pending[..pos]).into_owned();
+        self.pending.drain(..pos + 2);
+        Some(line)
+    }
+}
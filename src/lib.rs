@@ -8,9 +8,72 @@ use std::ffi::{CStr, CString};
 pub mod bindings;
 pub use bindings::*;
 
+mod addr;
+
+mod alt;
+pub use alt::*;
+
+mod buffer;
+pub use buffer::*;
+
+mod cache;
+pub use cache::*;
+
+mod cache_info;
+pub use cache_info::*;
+
+mod dns;
+pub use dns::*;
+
+mod executor;
+pub use executor::*;
+
+mod fetch;
+pub use fetch::*;
+
+mod header;
+pub use header::*;
+
+mod intercept;
+pub use intercept::*;
+
+#[cfg(feature = "log")]
+mod logger;
+#[cfg(feature = "log")]
+pub use logger::*;
+
+mod net;
+pub use net::*;
+
 mod remap;
 pub use remap::*;
 
+mod schedule;
+pub use schedule::*;
+
+mod session;
+pub use session::*;
+
+mod stats;
+pub use stats::*;
+
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "tracing")]
+pub use trace::*;
+
+mod transform;
+pub use transform::*;
+
+mod ts_url;
+pub use ts_url::*;
+
+mod txn;
+pub use txn::*;
+
+mod vconn;
+pub use vconn::*;
+
 pub fn ts_debug(tag: &str, message: &str) {
     let t = CString::new(tag).unwrap_or_default();
     let s = CString::new(message).unwrap_or_default();
@@ -0,0 +1,122 @@
+use crate::bindings::*;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::sync::{Arc, PoisonError, RwLock};
+
+const FORMAT: &[u8] = b"%s\0";
+
+/// A `log` backend writing to the ATS logs.
+///
+/// `Error` and `Warn` records go to `TSError`. The other levels go to
+/// `TSDebug`, tagged with the plugin tag if one is set or the module path
+/// otherwise, and are only formatted when the tag is enabled with
+/// `proxy.config.diags.debug.tags`.
+pub struct TsLogger {
+    tag: Option<Arc<CStr>>,
+    level: LevelFilter,
+    // Module path tags, so that checking a disabled tag does not allocate.
+    tags: RwLock<HashMap<String, Arc<CStr>>>,
+}
+
+impl TsLogger {
+    /// A logger tagging debug output with the module path of each record.
+    pub fn new() -> TsLogger {
+        TsLogger { tag: None, level: LevelFilter::Trace, tags: RwLock::default() }
+    }
+
+    /// A logger tagging all output with `tag`, usually the plugin name.
+    pub fn with_tag(tag: &str) -> TsLogger {
+        TsLogger {
+            tag: CString::new(tag).ok().map(Arc::from),
+            level: LevelFilter::Trace,
+            tags: RwLock::default(),
+        }
+    }
+
+    /// Drops records more verbose than `level`.
+    pub fn level(mut self, level: LevelFilter) -> TsLogger {
+        self.level = level;
+        self
+    }
+
+    /// Installs the logger, usually from `TSPluginInit`.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    /// The debug tag of records from `target`, if it is enabled.
+    fn debug_tag(&self, target: &str) -> Option<Arc<CStr>> {
+        let tag = match &self.tag {
+            Some(tag) => tag.clone(),
+            None => self.target_tag(target)?,
+        };
+        if unsafe { TSIsDebugTagSet(tag.as_ptr()) } == 0 {
+            return None;
+        }
+        Some(tag)
+    }
+
+    fn target_tag(&self, target: &str) -> Option<Arc<CStr>> {
+        if let Some(tag) = self.tags.read().unwrap_or_else(PoisonError::into_inner).get(target) {
+            return Some(tag.clone());
+        }
+
+        let tag: Arc<CStr> = CString::new(target).ok()?.into();
+        let mut tags = self.tags.write().unwrap_or_else(PoisonError::into_inner);
+        Some(tags.entry(target.to_string()).or_insert(tag).clone())
+    }
+}
+
+impl Default for TsLogger {
+    fn default() -> TsLogger {
+        TsLogger::new()
+    }
+}
+
+impl Log for TsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.level() > self.level {
+            return false;
+        }
+
+        match metadata.level() {
+            Level::Error | Level::Warn => true,
+            _ => self.debug_tag(metadata.target()).is_some(),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() > self.level {
+            return;
+        }
+
+        let format = FORMAT.as_ptr() as *const c_char;
+        match record.level() {
+            Level::Error | Level::Warn => {
+                let tag = match &self.tag {
+                    Some(tag) => tag.to_string_lossy().into_owned(),
+                    None => record.target().to_string(),
+                };
+                let message = match record.level() {
+                    Level::Error => format!("[{}] {}", tag, record.args()),
+                    _ => format!("[{}] warning: {}", tag, record.args()),
+                };
+                let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+                unsafe { TSError(format, message.as_ptr()) }
+            }
+            _ => {
+                let tag = match self.debug_tag(record.target()) {
+                    Some(tag) => tag,
+                    None => return,
+                };
+                let message = CString::new(record.args().to_string().replace('\0', "")).unwrap_or_default();
+                unsafe { TSDebug(tag.as_ptr(), format, message.as_ptr()) }
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
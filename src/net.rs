@@ -0,0 +1,432 @@
+use crate::addr::{RawSockAddr, AF_INET, AF_INET6};
+use crate::bindings::*;
+use crate::buffer::{IOBuffer, IOBufferReader};
+use crate::executor::lock;
+use crate::vconn::{VConn, Vio};
+use std::future::poll_fn;
+use std::io;
+use std::ffi::CString;
+use std::net::SocketAddr;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+// Writes wait for ATS to drain the buffer past this size.
+const WRITE_HIGH_WATER: i64 = 64 * 1024;
+
+/// An outbound TCP connection running on the ATS event loop.
+///
+/// Reads and writes are futures, to be awaited from `spawn`.
+pub struct TcpStream {
+    contp: TSCont,
+    mutex: TSMutex,
+    state: Arc<Mutex<StreamState>>,
+}
+
+// SAFETY: the continuation and its buffers are only used while holding the
+// continuation mutex, as ATS does for its own I/O.
+unsafe impl Send for TcpStream {}
+
+struct StreamState {
+    vc: Option<VConn>,
+    action: TSAction,
+    connected: bool,
+    eos: bool,
+    error: Option<(io::ErrorKind, &'static str)>,
+    read_buffer: IOBuffer,
+    read_reader: IOBufferReader,
+    read_vio: Option<Vio>,
+    write_buffer: IOBuffer,
+    write_reader: IOBufferReader,
+    write_vio: Option<Vio>,
+    connect_waker: Option<Waker>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+// SAFETY: see `TcpStream`.
+unsafe impl Send for StreamState {}
+
+impl StreamState {
+    fn wake_all(&mut self) {
+        let wakers = [self.connect_waker.take(), self.read_waker.take(), self.write_waker.take()];
+        for waker in wakers.iter().flatten() {
+            waker.wake_by_ref();
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        match self.error {
+            Some((kind, msg)) => Err(io::Error::new(kind, msg)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TcpStream {
+    /// Opens a connection to `addr` with `TSNetConnect`.
+    pub async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+        let to = RawSockAddr::new(&addr);
+        TcpStream::start(|contp| unsafe { TSNetConnect(contp, to.as_ptr()) }).await
+    }
+
+    /// Opens a connection to `to` that appears to originate from `from`,
+    /// which requires ATS to run with transparency enabled.
+    pub async fn connect_transparent(from: SocketAddr, to: SocketAddr) -> io::Result<TcpStream> {
+        let from = RawSockAddr::new(&from);
+        let to = RawSockAddr::new(&to);
+        TcpStream::start(|contp| unsafe { TSNetConnectTransparent(contp, from.as_ptr(), to.as_ptr()) }).await
+    }
+
+    async fn start<C: FnOnce(TSCont) -> TSAction>(connect: C) -> io::Result<TcpStream> {
+        let read_buffer = IOBuffer::new();
+        let read_reader = read_buffer.reader();
+        let write_buffer = IOBuffer::new();
+        let write_reader = write_buffer.reader();
+
+        let state = Arc::new(Mutex::new(StreamState {
+            vc: None,
+            action: ptr::null_mut(),
+            connected: false,
+            eos: false,
+            error: None,
+            read_buffer,
+            read_reader,
+            read_vio: None,
+            write_buffer,
+            write_reader,
+            write_vio: None,
+            connect_waker: None,
+            read_waker: None,
+            write_waker: None,
+        }));
+
+        let stream = unsafe {
+            let mutex = TSMutexCreate();
+            let contp = TSContCreate(Some(handle_stream_event), mutex);
+            TSContDataSet(contp, Arc::into_raw(state.clone()) as *mut c_void);
+
+            // The connect may complete synchronously and call back into the
+            // continuation, so only the ATS lock is held here.
+            TSMutexLock(mutex);
+            let action = connect(contp);
+            {
+                let mut state = lock(&state);
+                if !state.connected && state.error.is_none() && TSActionDone(action) == 0 {
+                    state.action = action;
+                }
+            }
+            TSMutexUnlock(mutex);
+
+            TcpStream { contp, mutex, state }
+        };
+
+        poll_fn(|cx| {
+            stream.with_state(|state| {
+                if let Err(err) = state.check() {
+                    return Poll::Ready(Err(err));
+                }
+                if state.connected {
+                    return Poll::Ready(Ok(()));
+                }
+                state.connect_waker = Some(cx.waker().clone());
+                Poll::Pending
+            })
+        })
+        .await?;
+
+        Ok(stream)
+    }
+
+    fn with_state<T, F: FnOnce(&mut StreamState) -> T>(&self, f: F) -> T {
+        unsafe {
+            TSMutexLock(self.mutex);
+            let result = f(&mut lock(&self.state));
+            TSMutexUnlock(self.mutex);
+            result
+        }
+    }
+
+    /// Reads into `buf`, resolving to 0 once the peer has closed the connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.with_state(|state| {
+            let n = state.read_reader.read(buf);
+            if n > 0 {
+                state.read_reader.consume(n as i64);
+                if let Some(vio) = state.read_vio {
+                    vio.reenable();
+                }
+                return Poll::Ready(Ok(n));
+            }
+
+            if let Err(err) = state.check() {
+                return Poll::Ready(Err(err));
+            }
+            if state.eos || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            state.read_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+
+    /// Queues `data` for writing, waiting while too much is already pending.
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_write(cx, data)).await
+    }
+
+    fn poll_write(&self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        self.with_state(|state| {
+            if let Err(err) = state.check() {
+                return Poll::Ready(Err(err));
+            }
+
+            let vio = match state.write_vio {
+                Some(vio) => vio,
+                None => return Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected, "not connected"))),
+            };
+
+            if state.write_reader.avail() >= WRITE_HIGH_WATER {
+                state.write_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let n = state.write_buffer.write(data);
+            vio.reenable();
+            Poll::Ready(Ok(n as usize))
+        })
+    }
+
+    /// Writes all of `data`, waiting for buffer space as needed.
+    pub async fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let n = self.write(data).await?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Waits until every queued byte has been handed to the kernel.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        poll_fn(|cx| {
+            self.with_state(|state| {
+                if let Err(err) = state.check() {
+                    return Poll::Ready(Err(err));
+                }
+                if state.write_reader.avail() == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                state.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
+    pub fn vconn(&self) -> Option<VConn> {
+        self.with_state(|state| state.vc)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        unsafe {
+            TSMutexLock(self.mutex);
+            {
+                let mut state = lock(&self.state);
+                if !state.action.is_null() {
+                    TSActionCancel(state.action);
+                    state.action = ptr::null_mut();
+                }
+                if let Some(vc) = state.vc.take() {
+                    vc.close();
+                }
+                state.wake_all();
+            }
+            TSMutexUnlock(self.mutex);
+
+            // Closed connections and cancelled connects send no more events.
+            drop(Arc::from_raw(TSContDataGet(self.contp) as *const Mutex<StreamState>));
+            TSContDataSet(self.contp, ptr::null_mut());
+            TSContDestroy(self.contp);
+        }
+    }
+}
+
+unsafe extern "C" fn handle_stream_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    let ptr = TSContDataGet(contp) as *const Mutex<StreamState>;
+    if ptr.is_null() {
+        return 0;
+    }
+    let mut state = lock(&*ptr);
+
+    match event {
+        TSEvent_TS_EVENT_NET_CONNECT => {
+            let vc = VConn::from_raw(edata as TSVConn);
+            state.action = ptr::null_mut();
+            state.connected = true;
+            state.read_vio = Some(vc.read(contp, &state.read_buffer, i64::MAX));
+            state.write_vio = Some(vc.write(contp, state.write_reader, i64::MAX));
+            state.vc = Some(vc);
+            if let Some(w) = state.connect_waker.take() {
+                w.wake();
+            }
+        }
+        TSEvent_TS_EVENT_NET_CONNECT_FAILED => {
+            state.action = ptr::null_mut();
+            state.error = Some((io::ErrorKind::ConnectionRefused, "NET_CONNECT_FAILED"));
+            state.wake_all();
+        }
+        TSEvent_TS_EVENT_VCONN_READ_READY => {
+            if let Some(w) = state.read_waker.take() {
+                w.wake();
+            }
+        }
+        TSEvent_TS_EVENT_VCONN_READ_COMPLETE | TSEvent_TS_EVENT_VCONN_EOS => {
+            state.eos = true;
+            if let Some(w) = state.read_waker.take() {
+                w.wake();
+            }
+        }
+        TSEvent_TS_EVENT_VCONN_WRITE_READY | TSEvent_TS_EVENT_VCONN_WRITE_COMPLETE => {
+            if let Some(w) = state.write_waker.take() {
+                w.wake();
+            }
+        }
+        TSEvent_TS_EVENT_VCONN_INACTIVITY_TIMEOUT | TSEvent_TS_EVENT_VCONN_ACTIVE_TIMEOUT => {
+            state.error = Some((io::ErrorKind::TimedOut, "connection timed out"));
+            state.wake_all();
+        }
+        _ => {
+            state.error = Some((io::ErrorKind::ConnectionReset, "connection error"));
+            state.wake_all();
+        }
+    }
+
+    0
+}
+
+type AcceptFn = Box<dyn Fn(VConn) + Send + Sync>;
+
+/// Accepts inbound connections and hands each one to a handler.
+///
+/// The handler owns the accepted `VConn` and must close it. Dropping the
+/// listener leaves it accepting for the lifetime of the plugin.
+pub struct Listener {
+    contp: TSCont,
+    action: TSAction,
+}
+
+// SAFETY: the action is only cancelled while holding the continuation mutex.
+unsafe impl Send for Listener {}
+
+impl Listener {
+    /// Listens on `port` on all IPv4 addresses with `TSNetAccept`.
+    pub fn bind<F: Fn(VConn) + Send + Sync + 'static>(port: u16, handler: F) -> Listener {
+        Listener::accept(port, AF_INET, Box::new(handler))
+    }
+
+    /// Listens on `port` on all IPv6 addresses with `TSNetAccept`.
+    pub fn bind_ipv6<F: Fn(VConn) + Send + Sync + 'static>(port: u16, handler: F) -> Listener {
+        Listener::accept(port, AF_INET6, Box::new(handler))
+    }
+
+    fn accept(port: u16, domain: c_int, handler: AcceptFn) -> Listener {
+        unsafe {
+            let contp = accept_cont(handler);
+            let mutex = TSContMutexGet(contp);
+            TSMutexLock(mutex);
+            let action = TSNetAccept(contp, port as c_int, domain, -1);
+            TSMutexUnlock(mutex);
+
+            Listener { contp, action }
+        }
+    }
+
+    /// Listens on a port described the way `proxy.config.http.server_ports`
+    /// does, e.g. `"8443:ssl"` or `"ipv6:9000"`.
+    pub fn bind_descriptor<F: Fn(VConn) + Send + Sync + 'static>(descriptor: &str, handler: F) -> Result<Listener, String> {
+        let descriptor = CString::new(descriptor).map_err(|e| e.to_string())?;
+
+        unsafe {
+            let port = TSPortDescriptorParse(descriptor.as_ptr());
+            if port.is_null() {
+                return Err(format!("invalid port descriptor {:?}", descriptor));
+            }
+
+            let contp = accept_cont(Box::new(handler));
+            if TSPortDescriptorAccept(port, contp) != TSReturnCode_TS_SUCCESS {
+                destroy_accept_cont(contp);
+                return Err(format!("failed to listen on {:?}", descriptor));
+            }
+
+            Ok(Listener { contp, action: ptr::null_mut() })
+        }
+    }
+
+    /// Receives connections that negotiate `protocol` via ALPN/NPN on the
+    /// TLS ports of the proxy. Must be called from `TSPluginInit`.
+    pub fn named_protocol<F: Fn(VConn) + Send + Sync + 'static>(protocol: &str, handler: F) -> Result<Listener, String> {
+        let name = CString::new(protocol).map_err(|e| e.to_string())?;
+
+        unsafe {
+            let contp = accept_cont(Box::new(handler));
+            if TSNetAcceptNamedProtocol(contp, name.as_ptr()) != TSReturnCode_TS_SUCCESS {
+                destroy_accept_cont(contp);
+                return Err(format!("failed to register protocol {}", protocol));
+            }
+
+            Ok(Listener { contp, action: ptr::null_mut() })
+        }
+    }
+
+    /// Stops accepting. Only listeners created with `bind` can be stopped,
+    /// ATS has no way to close the others and they keep running.
+    pub fn close(self) {
+        if self.action.is_null() {
+            return;
+        }
+
+        unsafe {
+            let mutex = TSContMutexGet(self.contp);
+            TSMutexLock(mutex);
+            TSActionCancel(self.action);
+            TSMutexUnlock(mutex);
+
+            destroy_accept_cont(self.contp);
+        }
+    }
+}
+
+unsafe fn accept_cont(handler: AcceptFn) -> TSCont {
+    let contp = TSContCreate(Some(handle_accept_event), TSMutexCreate());
+    TSContDataSet(contp, Box::into_raw(Box::new(handler)) as *mut c_void);
+    contp
+}
+
+unsafe fn destroy_accept_cont(contp: TSCont) {
+    drop(Box::from_raw(TSContDataGet(contp) as *mut AcceptFn));
+    TSContDataSet(contp, ptr::null_mut());
+    TSContDestroy(contp);
+}
+
+unsafe extern "C" fn handle_accept_event(contp: TSCont, event: TSEvent, edata: *mut c_void) -> c_int {
+    let handler = TSContDataGet(contp) as *const AcceptFn;
+    if handler.is_null() {
+        return 0;
+    }
+
+    match event {
+        TSEvent_TS_EVENT_NET_ACCEPT => (*handler)(VConn::from_raw(edata as TSVConn)),
+        TSEvent_TS_EVENT_NET_ACCEPT_FAILED => crate::ts_error("net accept failed"),
+        _ => {}
+    }
+
+    0
+}
@@ -0,0 +1,218 @@
+use crate::bindings::*;
+use crate::executor::{lock, oneshot};
+use crate::txn::Transaction;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The ATS thread pool a scheduled callback runs on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadPool {
+    Net,
+    Task,
+}
+
+impl ThreadPool {
+    fn as_raw(self) -> TSThreadPool {
+        match self {
+            ThreadPool::Net => TSThreadPool_TS_THREAD_POOL_NET,
+            ThreadPool::Task => TSThreadPool_TS_THREAD_POOL_TASK,
+        }
+    }
+}
+
+/// Runs `f` once after `delay` on the net threads.
+pub fn schedule_once<F: FnOnce() + Send + 'static>(delay: Duration, f: F) -> ScheduleHandle {
+    schedule_once_on(ThreadPool::Net, delay, f)
+}
+
+/// Runs `f` once after `delay` on the given thread pool.
+pub fn schedule_once_on<F: FnOnce() + Send + 'static>(pool: ThreadPool, delay: Duration, f: F) -> ScheduleHandle {
+    ScheduleHandle::new(Callback::Once(Some(Box::new(f))), |contp| unsafe {
+        TSContSchedule(contp, millis(delay), pool.as_raw())
+    })
+}
+
+/// Runs `f` every `period` on the net threads, until cancelled.
+pub fn schedule_every<F: FnMut() + Send + 'static>(period: Duration, f: F) -> ScheduleHandle {
+    schedule_every_on(ThreadPool::Net, period, f)
+}
+
+/// Runs `f` every `period` on the given thread pool, until cancelled.
+pub fn schedule_every_on<F: FnMut() + Send + 'static>(pool: ThreadPool, period: Duration, f: F) -> ScheduleHandle {
+    ScheduleHandle::new(Callback::Every(Some(Box::new(f))), |contp| unsafe {
+        TSContScheduleEvery(contp, millis(period), pool.as_raw())
+    })
+}
+
+/// Resolves after `delay`, for use with `spawn`.
+pub async fn sleep(delay: Duration) {
+    let (tx, rx) = oneshot();
+    let _handle = schedule_once(delay, move || tx.send(()));
+    rx.await;
+}
+
+impl Transaction {
+    /// Runs `f` after `delay` on the thread of the transaction. The
+    /// transaction must be paused in a hook, `f` is expected to reenable it.
+    pub fn schedule<F: FnOnce(Transaction) + Send + 'static>(&self, delay: Duration, f: F) -> ScheduleHandle {
+        let txn = *self;
+        ScheduleHandle::new(Callback::Once(Some(Box::new(move || f(txn)))), |contp| unsafe {
+            TSHttpSchedule(contp, txn.as_raw(), millis(delay))
+        })
+    }
+}
+
+fn millis(d: Duration) -> TSHRTime {
+    d.as_millis().min(TSHRTime::MAX as u128) as TSHRTime
+}
+
+enum Callback {
+    Once(Option<Box<dyn FnOnce() + Send>>),
+    Every(Option<Box<dyn FnMut() + Send>>),
+}
+
+struct Timer {
+    action: TSAction,
+    callback: Callback,
+    finished: bool,
+    detached: bool,
+}
+
+// SAFETY: the action is only cancelled while holding the continuation mutex.
+unsafe impl Send for Timer {}
+
+/// A scheduled callback.
+///
+/// Dropping the handle does not cancel the callback, it keeps running on
+/// its own. Use `cancel` to stop it.
+pub struct ScheduleHandle {
+    contp: TSCont,
+    timer: Arc<Mutex<Timer>>,
+}
+
+// SAFETY: the continuation is only touched while holding its mutex, and it
+// is not destroyed while the handle is alive.
+unsafe impl Send for ScheduleHandle {}
+
+impl ScheduleHandle {
+    fn new<S: FnOnce(TSCont) -> TSAction>(callback: Callback, schedule: S) -> ScheduleHandle {
+        let timer = Arc::new(Mutex::new(Timer {
+            action: ptr::null_mut(),
+            callback,
+            finished: false,
+            detached: false,
+        }));
+
+        unsafe {
+            let mutex = TSMutexCreate();
+            let contp = TSContCreate(Some(handle_timer_event), mutex);
+            TSContDataSet(contp, Arc::into_raw(timer.clone()) as *mut c_void);
+
+            // Hold the continuation lock so the callback cannot run before
+            // the action is recorded.
+            TSMutexLock(mutex);
+            let action = schedule(contp);
+            lock(&timer).action = action;
+            TSMutexUnlock(mutex);
+
+            ScheduleHandle { contp, timer }
+        }
+    }
+
+    /// Stops the callback. A callback that is already running completes,
+    /// but will not be called again.
+    pub fn cancel(&self) {
+        unsafe {
+            let mutex = TSContMutexGet(self.contp);
+            TSMutexLock(mutex);
+
+            let mut timer = lock(&self.timer);
+            if !timer.finished {
+                timer.finished = true;
+                if !timer.action.is_null() {
+                    TSActionCancel(timer.action);
+                }
+                timer.callback = Callback::Once(None);
+            }
+            drop(timer);
+
+            TSMutexUnlock(mutex);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        lock(&self.timer).finished
+    }
+}
+
+impl Drop for ScheduleHandle {
+    fn drop(&mut self) {
+        unsafe {
+            let mutex = TSContMutexGet(self.contp);
+            TSMutexLock(mutex);
+
+            let destroy = {
+                let mut timer = lock(&self.timer);
+                timer.detached = true;
+                timer.finished
+            };
+
+            TSMutexUnlock(mutex);
+
+            // Finished timers get no more events, so it is safe to destroy
+            // the continuation outside of its lock.
+            if destroy {
+                destroy_timer(self.contp);
+            }
+        }
+    }
+}
+
+unsafe fn destroy_timer(contp: TSCont) {
+    drop(Arc::from_raw(TSContDataGet(contp) as *const Mutex<Timer>));
+    TSContDataSet(contp, ptr::null_mut());
+    TSContDestroy(contp);
+}
+
+unsafe extern "C" fn handle_timer_event(contp: TSCont, _event: TSEvent, _edata: *mut c_void) -> c_int {
+    let timer = &*(TSContDataGet(contp) as *const Mutex<Timer>);
+
+    // The callback runs without the timer lock, so it can cancel itself.
+    let callback = {
+        let mut timer = lock(timer);
+        if timer.finished {
+            return 0;
+        }
+        std::mem::replace(&mut timer.callback, Callback::Once(None))
+    };
+
+    match callback {
+        Callback::Once(f) => {
+            if let Some(f) = f {
+                f();
+            }
+            lock(timer).finished = true;
+        }
+        Callback::Every(mut f) => {
+            if let Some(f) = f.as_mut() {
+                f();
+            }
+            let mut timer = lock(timer);
+            if !timer.finished {
+                timer.callback = Callback::Every(f);
+            }
+        }
+    }
+
+    let destroy = {
+        let timer = lock(timer);
+        timer.finished && timer.detached
+    };
+    if destroy {
+        destroy_timer(contp);
+    }
+
+    0
+}
@@ -0,0 +1,46 @@
+use crate::addr::socket_addr;
+use crate::bindings::*;
+use std::net::SocketAddr;
+use std::os::raw::c_int;
+
+/// A thin, copyable handle over a client `TSHttpSsn`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Session {
+    ssn: TSHttpSsn,
+}
+
+// SAFETY: see `Transaction`.
+unsafe impl Send for Session {}
+
+impl Session {
+    pub fn from_raw(ssn: TSHttpSsn) -> Session {
+        Session { ssn }
+    }
+
+    pub fn as_raw(&self) -> TSHttpSsn {
+        self.ssn
+    }
+
+    /// Whether debugging is enabled for the session.
+    pub fn debug_enabled(&self) -> bool {
+        let mut on: c_int = 0;
+        unsafe { TSHttpSsnDebugGet(self.ssn, &mut on) };
+        on != 0
+    }
+
+    /// The address of the client.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSHttpSsnClientAddrGet(self.ssn)) }
+    }
+
+    /// The local address the client connected to.
+    pub fn incoming_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSHttpSsnIncomingAddrGet(self.ssn)) }
+    }
+}
+
+impl From<TSHttpSsn> for Session {
+    fn from(ssn: TSHttpSsn) -> Session {
+        Session::from_raw(ssn)
+    }
+}
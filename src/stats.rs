@@ -0,0 +1,397 @@
+use crate::bindings::*;
+use crate::executor::lock;
+use crate::schedule::schedule_every;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once, PoisonError, RwLock};
+use std::time::Duration;
+
+// Updates made off ATS threads, summed per stat id until the next flush.
+static PENDING: Mutex<Vec<(c_int, i64)>> = Mutex::new(Vec::new());
+static FLUSH: Once = Once::new();
+const FLUSH_PERIOD: Duration = Duration::from_secs(1);
+
+/// Whether a stat keeps its value across ATS restarts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatPersistence {
+    Persistent,
+    NonPersistent,
+}
+
+/// How the per-thread values of a stat are combined.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatSync {
+    Sum,
+    Count,
+    Avg,
+    TimeAvg,
+}
+
+/// Creates stats with non-default options.
+///
+/// A stat that already exists, e.g. after a configuration reload, is
+/// reused as is, including its original options.
+pub struct StatBuilder {
+    name: String,
+    persistence: StatPersistence,
+    sync: StatSync,
+}
+
+impl StatBuilder {
+    pub fn new(name: &str) -> StatBuilder {
+        StatBuilder {
+            name: name.to_string(),
+            persistence: StatPersistence::NonPersistent,
+            sync: StatSync::Sum,
+        }
+    }
+
+    pub fn persistence(mut self, persistence: StatPersistence) -> StatBuilder {
+        self.persistence = persistence;
+        self
+    }
+
+    pub fn sync(mut self, sync: StatSync) -> StatBuilder {
+        self.sync = sync;
+        self
+    }
+
+    pub fn counter(self) -> Result<Counter, String> {
+        Ok(Counter { id: self.create()? })
+    }
+
+    pub fn gauge(self) -> Result<Gauge, String> {
+        Ok(Gauge { id: self.create()? })
+    }
+
+    pub fn sum(self) -> Result<Sum, String> {
+        Ok(Sum { id: self.create()? })
+    }
+
+    fn create(self) -> Result<c_int, String> {
+        let name = CString::new(self.name.as_str()).map_err(|e| e.to_string())?;
+
+        unsafe {
+            let mut id: c_int = -1;
+            if TSStatFindName(name.as_ptr(), &mut id) == TSReturnCode_TS_SUCCESS {
+                return Ok(id);
+            }
+
+            let persistence = match self.persistence {
+                StatPersistence::Persistent => TSStatPersistence_TS_STAT_PERSISTENT,
+                StatPersistence::NonPersistent => TSStatPersistence_TS_STAT_NON_PERSISTENT,
+            };
+            let sync = match self.sync {
+                StatSync::Sum => TSStatSync_TS_STAT_SYNC_SUM,
+                StatSync::Count => TSStatSync_TS_STAT_SYNC_COUNT,
+                StatSync::Avg => TSStatSync_TS_STAT_SYNC_AVG,
+                StatSync::TimeAvg => TSStatSync_TS_STAT_SYNC_TIMEAVG,
+            };
+
+            let id = TSStatCreate(name.as_ptr(), TSRecordDataType_TS_RECORDDATATYPE_INT, persistence, sync);
+            if id < 0 {
+                return Err(format!("unable to create stat {}", self.name));
+            }
+
+            // Continuations can only be created on ATS threads, stats
+            // usually are created in `TSPluginInit`.
+            if !TSThreadSelf().is_null() {
+                FLUSH.call_once(|| drop(schedule_every(FLUSH_PERIOD, flush_pending)));
+            }
+            Ok(id)
+        }
+    }
+}
+
+/// Adds `n` to stat `id`. `TSStatIntIncrement` writes to the slot of the
+/// calling ATS thread, so updates from other threads are queued instead.
+fn increment(id: c_int, n: i64) {
+    if unsafe { TSThreadSelf().is_null() } {
+        let mut pending = lock(&PENDING);
+        match pending.iter_mut().find(|(pending_id, _)| *pending_id == id) {
+            Some((_, total)) => *total += n,
+            None => pending.push((id, n)),
+        }
+    } else {
+        unsafe { TSStatIntIncrement(id, n) }
+    }
+}
+
+fn flush_pending() {
+    let pending = std::mem::take(&mut *lock(&PENDING));
+    for (id, n) in pending {
+        unsafe { TSStatIntIncrement(id, n) }
+    }
+}
+
+/// A monotonically increasing count, e.g. of requests.
+///
+/// Updates can be made from any thread. On ATS threads they go straight to
+/// the slot of the thread, from other threads they are queued and applied by
+/// a net thread about once a second.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Counter {
+    id: c_int,
+}
+
+impl Counter {
+    /// Creates the counter, or reuses the stat with this name.
+    pub fn new(name: &str) -> Result<Counter, String> {
+        StatBuilder::new(name).counter()
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    pub fn add(&self, n: u64) {
+        increment(self.id, n as TSMgmtInt)
+    }
+
+    pub fn get(&self) -> i64 {
+        unsafe { TSStatIntGet(self.id) }
+    }
+}
+
+/// A value that goes up and down, e.g. open connections.
+///
+/// Per-thread values are summed, so there is no way to set an absolute
+/// value. Like `Counter`, it can be updated from any thread.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Gauge {
+    id: c_int,
+}
+
+impl Gauge {
+    /// Creates the gauge, or reuses the stat with this name.
+    pub fn new(name: &str) -> Result<Gauge, String> {
+        StatBuilder::new(name).gauge()
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    pub fn dec(&self) {
+        self.sub(1)
+    }
+
+    pub fn add(&self, n: i64) {
+        increment(self.id, n)
+    }
+
+    pub fn sub(&self, n: i64) {
+        increment(self.id, -n)
+    }
+
+    pub fn get(&self) -> i64 {
+        unsafe { TSStatIntGet(self.id) }
+    }
+}
+
+/// A running total of amounts, e.g. bytes sent or time spent. Like
+/// `Counter`, it can be updated from any thread.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sum {
+    id: c_int,
+}
+
+impl Sum {
+    /// Creates the sum, or reuses the stat with this name.
+    pub fn new(name: &str) -> Result<Sum, String> {
+        StatBuilder::new(name).sum()
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn add(&self, amount: u64) {
+        increment(self.id, amount as TSMgmtInt)
+    }
+
+    pub fn get(&self) -> i64 {
+        unsafe { TSStatIntGet(self.id) }
+    }
+}
+
+/// A latency distribution over fixed buckets, stored as a family of stats:
+/// `name.bucket.le_<bound>` for each bucket plus `name.bucket.le_inf`,
+/// `name.count` and `name.sum` (in milliseconds). Buckets are cumulative,
+/// as in Prometheus.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: Vec<Duration>,
+    buckets: Vec<Counter>,
+    count: Counter,
+    sum: Sum,
+}
+
+impl Histogram {
+    /// The default buckets, from 5ms to 10s.
+    pub const DEFAULT_BUCKETS: &'static [u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+    /// Creates a histogram with the default buckets.
+    pub fn new(name: &str) -> Result<Histogram, String> {
+        let bounds: Vec<Duration> = Histogram::DEFAULT_BUCKETS.iter().map(|ms| Duration::from_millis(*ms)).collect();
+        Histogram::with_buckets(name, &bounds)
+    }
+
+    /// Creates a histogram with the given upper bucket bounds.
+    pub fn with_buckets(name: &str, bounds: &[Duration]) -> Result<Histogram, String> {
+        let mut bounds = bounds.to_vec();
+        bounds.sort();
+        bounds.dedup();
+
+        let mut buckets = Vec::with_capacity(bounds.len() + 1);
+        for bound in &bounds {
+            buckets.push(Counter::new(&format!("{}.bucket.le_{}", name, bucket_label(*bound)))?);
+        }
+        buckets.push(Counter::new(&format!("{}.bucket.le_inf", name))?);
+
+        Ok(Histogram {
+            bounds,
+            buckets,
+            count: Counter::new(&format!("{}.count", name))?,
+            sum: Sum::new(&format!("{}.sum", name))?,
+        })
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let first = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        for bucket in &self.buckets[first..] {
+            bucket.inc();
+        }
+
+        self.count.inc();
+        self.sum.add(value.as_millis() as u64);
+    }
+}
+
+/// Formats a bound as `10ms` or `1s`, falling back to microseconds.
+fn bucket_label(bound: Duration) -> String {
+    let micros = bound.as_micros();
+    if micros.is_multiple_of(1_000_000) {
+        format!("{}s", micros / 1_000_000)
+    } else if micros.is_multiple_of(1_000) {
+        format!("{}ms", micros / 1_000)
+    } else {
+        format!("{}us", micros)
+    }
+}
+
+/// A family of counters keyed by label values, e.g. `plugin.requests` by
+/// origin and status.
+///
+/// Each combination of values gets its own stat, created on first use and
+/// named `name.label1.value1.label2.value2` in label order. Bytes of values
+/// other than ASCII alphanumerics and `-`, including `_` itself, are written
+/// as `_` and two hex digits, e.g. `a.b` as `a_2Eb`, so distinct values keep
+/// distinct stats. Empty values are written as a bare `_`.
+/// ATS has a fixed size stat table, so the number of combinations is capped.
+pub struct CounterVec {
+    name: String,
+    labels: Vec<String>,
+    limit: usize,
+    counters: RwLock<HashMap<Vec<String>, Counter>>,
+    logged: AtomicBool,
+}
+
+impl CounterVec {
+    /// The default cap on label combinations.
+    pub const DEFAULT_LIMIT: usize = 1000;
+
+    pub fn new(name: &str, labels: &[&str]) -> CounterVec {
+        CounterVec {
+            name: name.to_string(),
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            limit: CounterVec::DEFAULT_LIMIT,
+            counters: RwLock::new(HashMap::new()),
+            logged: AtomicBool::new(false),
+        }
+    }
+
+    /// Caps the number of label combinations.
+    pub fn limit(mut self, limit: usize) -> CounterVec {
+        self.limit = limit;
+        self
+    }
+
+    /// The counter for `values`, given in label order. Fails once the limit
+    /// of combinations is reached.
+    pub fn with_label_values(&self, values: &[&str]) -> Result<Counter, String> {
+        if values.len() != self.labels.len() {
+            return Err(format!("{} expects {} label values, got {}", self.name, self.labels.len(), values.len()));
+        }
+
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        {
+            let counters = self.counters.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some(counter) = counters.get(&key) {
+                return Ok(*counter);
+            }
+            if counters.len() >= self.limit {
+                return Err(self.limit_error());
+            }
+        }
+
+        let mut counters = self.counters.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counter) = counters.get(&key) {
+            return Ok(*counter);
+        }
+        if counters.len() >= self.limit {
+            return Err(self.limit_error());
+        }
+
+        let counter = Counter::new(&self.stat_name(values))?;
+        counters.insert(key, counter);
+        Ok(counter)
+    }
+
+    /// Increments the counter for `values`. The first counter that cannot be
+    /// created is logged as an error, later failures are dropped silently.
+    pub fn inc(&self, values: &[&str]) {
+        match self.with_label_values(values) {
+            Ok(counter) => counter.inc(),
+            Err(e) => {
+                if !self.logged.swap(true, Ordering::Relaxed) {
+                    crate::ts_error(&e);
+                }
+            }
+        }
+    }
+
+    fn limit_error(&self) -> String {
+        format!("{} reached its limit of {} label combinations", self.name, self.limit)
+    }
+
+    fn stat_name(&self, values: &[&str]) -> String {
+        let mut name = self.name.clone();
+        for (label, value) in self.labels.iter().zip(values) {
+            name.push('.');
+            name.push_str(label);
+            name.push('.');
+            if value.is_empty() {
+                name.push('_');
+            }
+            for b in value.bytes() {
+                if b.is_ascii_alphanumeric() || b == b'-' {
+                    name.push(b as char);
+                } else {
+                    name.push_str(&format!("_{:02X}", b));
+                }
+            }
+        }
+        name
+    }
+}
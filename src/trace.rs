@@ -0,0 +1,146 @@
+use crate::bindings::*;
+use crate::txn::Transaction;
+use std::ffi::CString;
+use std::fmt::{self, Write};
+use std::os::raw::{c_char, c_int};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Span, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+const FORMAT: &[u8] = b"%s\0";
+
+// The span fields set by `Transaction::span`.
+const TXN_ID_FIELD: &str = "ts_txn_id";
+const TXN_DEBUG_FIELD: &str = "ts_txn_debug";
+
+/// A `tracing_subscriber` layer writing events to the ATS logs.
+///
+/// `ERROR` and `WARN` events go to `TSError`. Other events go to `TSDebug`
+/// under the layer tag, and are only formatted when the tag is enabled or
+/// when they happen inside the span of a transaction that has debugging
+/// turned on, e.g. with the `xdebug` plugin or `TSHttpTxnDebugSet`.
+pub struct TsLayer {
+    tag: CString,
+}
+
+impl TsLayer {
+    pub fn new(tag: &str) -> TsLayer {
+        TsLayer { tag: CString::new(tag).unwrap_or_default() }
+    }
+}
+
+impl Transaction {
+    /// A span that tags the events within it with the id of the
+    /// transaction, and enables them if the transaction is debugged.
+    pub fn span(&self) -> Span {
+        // The debug flag is read once here, while the transaction is known
+        // to be alive.
+        tracing::info_span!("txn", ts_txn_id = self.id(), ts_txn_debug = self.debug_enabled())
+    }
+}
+
+/// Stored in the extensions of transaction spans.
+struct TxnContext {
+    id: u64,
+    debug: bool,
+}
+
+impl<S> Layer<S> for TsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = TxnVisitor { id: None, debug: false };
+        attrs.record(&mut visitor);
+
+        if let (Some(txn_id), Some(span)) = (visitor.id, ctx.span(id)) {
+            span.extensions_mut().insert(TxnContext { id: txn_id, debug: visitor.debug });
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        let error = level == Level::ERROR || level == Level::WARN;
+
+        let mut txn = None;
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(context) = span.extensions().get::<TxnContext>() {
+                    txn = Some((context.id, context.debug));
+                    break;
+                }
+            }
+        }
+        let debug = txn.map(|(_, debug)| debug).unwrap_or(false);
+
+        if !error && !debug && unsafe { TSIsDebugTagSet(self.tag.as_ptr()) == 0 } {
+            return;
+        }
+
+        let mut message = String::new();
+        if error {
+            let _ = write!(message, "[{}] ", self.tag.to_string_lossy());
+        }
+        if let Some((id, _)) = txn {
+            let _ = write!(message, "[txn {}] ", id);
+        }
+        if level == Level::WARN {
+            message.push_str("warning: ");
+        }
+        event.record(&mut MessageVisitor(&mut message));
+        let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+
+        unsafe {
+            let format = FORMAT.as_ptr() as *const c_char;
+            if error {
+                TSError(format, message.as_ptr());
+            } else {
+                TSDebugSpecific(debug as c_int, self.tag.as_ptr(), format, message.as_ptr());
+            }
+        }
+    }
+}
+
+struct TxnVisitor {
+    id: Option<u64>,
+    debug: bool,
+}
+
+impl Visit for TxnVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == TXN_ID_FIELD {
+            self.id = Some(value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == TXN_DEBUG_FIELD {
+            self.debug = value;
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+/// Writes the message of an event followed by its other fields as `key=value`.
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0.push_str(value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::bindings::*;
+use crate::buffer::{IOBuffer, IOBufferReader};
+use crate::txn::Transaction;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+/// A body transformation applied as data flows through ATS.
+pub trait Transform: Send {
+    /// Called with each chunk of the body as it becomes available.
+    fn write(&mut self, input: &[u8], output: &mut Vec<u8>);
+
+    /// Called once the whole body has been consumed.
+    fn finish(&mut self, _output: &mut Vec<u8>) {}
+}
+
+/// Controls which version of a transformed response ATS stores in its cache.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransformCache {
+    Transformed,
+    Untransformed,
+    Both,
+    None,
+}
+
+impl TransformCache {
+    fn apply(self, txn: Transaction) {
+        let (transformed, untransformed) = match self {
+            TransformCache::Transformed => (1, 0),
+            TransformCache::Untransformed => (0, 1),
+            TransformCache::Both => (1, 1),
+            TransformCache::None => (0, 0),
+        };
+
+        unsafe {
+            TSHttpTxnTransformedRespCache(txn.as_raw(), transformed);
+            TSHttpTxnUntransformedRespCache(txn.as_raw(), untransformed);
+        }
+    }
+}
+
+/// Registers a `Transform` on a transaction's request or response body.
+pub struct TransformBuilder {
+    txn: Transaction,
+    hook: TSHttpHookID,
+    cache: Option<TransformCache>,
+}
+
+impl TransformBuilder {
+    pub fn request(txn: Transaction) -> TransformBuilder {
+        TransformBuilder {
+            txn,
+            hook: TSHttpHookID_TS_HTTP_REQUEST_TRANSFORM_HOOK,
+            cache: None,
+        }
+    }
+
+    pub fn response(txn: Transaction) -> TransformBuilder {
+        TransformBuilder {
+            txn,
+            hook: TSHttpHookID_TS_HTTP_RESPONSE_TRANSFORM_HOOK,
+            cache: None,
+        }
+    }
+
+    /// Chooses what gets cached for a response transform. ATS defaults to
+    /// caching only the transformed response when this is not set.
+    /// It has no effect on request transforms.
+    pub fn cache(mut self, cache: TransformCache) -> TransformBuilder {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Adds the transform to the transaction. Call it from a hook that runs
+    /// before the body is read, e.g. `TS_HTTP_READ_RESPONSE_HDR_HOOK`.
+    pub fn register<T: Transform + 'static>(self, transform: T) {
+        if self.hook == TSHttpHookID_TS_HTTP_RESPONSE_TRANSFORM_HOOK {
+            if let Some(cache) = self.cache {
+                cache.apply(self.txn);
+            }
+        }
+
+        let state = Box::new(TransformState {
+            transform: Box::new(transform),
+            output: None,
+            output_vio: ptr::null_mut(),
+            written: 0,
+            finished: false,
+        });
+
+        unsafe {
+            let contp = TSTransformCreate(Some(handle_transform_event), self.txn.as_raw());
+            TSContDataSet(contp, Box::into_raw(state) as *mut c_void);
+            TSHttpTxnHookAdd(self.txn.as_raw(), self.hook, contp);
+        }
+    }
+}
+
+struct TransformState {
+    transform: Box<dyn Transform>,
+    output: Option<IOBuffer>,
+    output_vio: TSVIO,
+    written: i64,
+    finished: bool,
+}
+
+impl TransformState {
+    fn emit(&mut self, data: &[u8]) {
+        if let Some(output) = self.output.as_ref() {
+            self.written += output.write(data);
+        }
+    }
+}
+
+unsafe extern "C" fn handle_transform_event(contp: TSCont, event: TSEvent, _edata: *mut c_void) -> c_int {
+    let state = TSContDataGet(contp) as *mut TransformState;
+
+    if TSVConnClosedGet(contp) != 0 {
+        if !state.is_null() {
+            drop(Box::from_raw(state));
+        }
+        TSContDestroy(contp);
+        return 0;
+    }
+
+    match event {
+        TSEvent_TS_EVENT_ERROR => {
+            let input_vio = TSVConnWriteVIOGet(contp);
+            TSContCall(TSVIOContGet(input_vio), TSEvent_TS_EVENT_ERROR, input_vio as *mut c_void);
+        }
+        TSEvent_TS_EVENT_VCONN_WRITE_COMPLETE => {
+            TSVConnShutdown(TSTransformOutputVConnGet(contp), 0, 1);
+        }
+        _ => {
+            if !state.is_null() {
+                transform_input(contp, &mut *state);
+            }
+        }
+    }
+
+    0
+}
+
+unsafe fn transform_input(contp: TSCont, state: &mut TransformState) {
+    let input_vio = TSVConnWriteVIOGet(contp);
+
+    if state.output.is_none() {
+        let output = IOBuffer::new();
+        let reader = output.reader();
+        state.output_vio = TSVConnWrite(TSTransformOutputVConnGet(contp), contp, reader.as_raw(), i64::MAX);
+        state.output = Some(output);
+    }
+
+    // A null buffer means the upstream writer has shut down, flush what we have.
+    if TSVIOBufferGet(input_vio).is_null() {
+        finish_output(state);
+        return;
+    }
+
+    let mut towrite = TSVIONTodoGet(input_vio);
+    if towrite > 0 {
+        let reader = IOBufferReader::from_raw(TSVIOReaderGet(input_vio));
+        towrite = towrite.min(reader.avail());
+
+        if towrite > 0 {
+            let mut input = Vec::with_capacity(towrite as usize);
+            reader.read_to(&mut input, towrite);
+            reader.consume(towrite);
+            TSVIONDoneSet(input_vio, TSVIONDoneGet(input_vio) + towrite);
+
+            let mut output = Vec::new();
+            state.transform.write(&input, &mut output);
+            state.emit(&output);
+        }
+    }
+
+    if TSVIONTodoGet(input_vio) > 0 {
+        if towrite > 0 {
+            TSVIOReenable(state.output_vio);
+            TSContCall(TSVIOContGet(input_vio), TSEvent_TS_EVENT_VCONN_WRITE_READY, input_vio as *mut c_void);
+        }
+    } else {
+        finish_output(state);
+        TSContCall(TSVIOContGet(input_vio), TSEvent_TS_EVENT_VCONN_WRITE_COMPLETE, input_vio as *mut c_void);
+    }
+}
+
+unsafe fn finish_output(state: &mut TransformState) {
+    if !state.finished {
+        state.finished = true;
+
+        let mut output = Vec::new();
+        state.transform.finish(&mut output);
+        state.emit(&output);
+    }
+
+    TSVIONBytesSet(state.output_vio, state.written);
+    TSVIOReenable(state.output_vio);
+}
@@ -0,0 +1,152 @@
+use crate::bindings::*;
+use crate::header::{ts_owned_string, ts_string};
+use std::fmt;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+type PartGetter = unsafe extern "C" fn(TSMBuffer, TSMLoc, *mut c_int) -> *const c_char;
+type PartSetter = unsafe extern "C" fn(TSMBuffer, TSMLoc, *const c_char, c_int) -> TSReturnCode;
+
+/// A URL stored in its own `TSMBuffer`, as the ATS URL functions expect.
+pub struct TsUrl {
+    bufp: TSMBuffer,
+    urlp: TSMLoc,
+}
+
+// SAFETY: marshal buffers are not tied to the thread that created them.
+unsafe impl Send for TsUrl {}
+
+impl TsUrl {
+    /// Creates an empty URL, e.g. to be filled in by ATS.
+    pub fn new() -> TsUrl {
+        unsafe {
+            let bufp = TSMBufferCreate();
+            let mut urlp: TSMLoc = ptr::null_mut();
+            TSUrlCreate(bufp, &mut urlp);
+            TsUrl { bufp, urlp }
+        }
+    }
+
+    pub fn parse(url: &str) -> Result<TsUrl, String> {
+        let parsed = TsUrl::new();
+
+        unsafe {
+            let mut start = url.as_ptr() as *const c_char;
+            let end = start.add(url.len());
+            if TSUrlParse(parsed.bufp, parsed.urlp, &mut start, end) != TSParseResult_TS_PARSE_DONE {
+                return Err(format!("invalid url: {}", url));
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn as_raw(&self) -> (TSMBuffer, TSMLoc) {
+        (self.bufp, self.urlp)
+    }
+
+    pub fn scheme(&self) -> Option<String> {
+        self.part(TSUrlSchemeGet)
+    }
+
+    pub fn set_scheme(&self, scheme: &str) -> Result<(), String> {
+        self.set_part(TSUrlSchemeSet, "scheme", scheme)
+    }
+
+    pub fn host(&self) -> Option<String> {
+        self.part(TSUrlHostGet)
+    }
+
+    pub fn set_host(&self, host: &str) -> Result<(), String> {
+        self.set_part(TSUrlHostSet, "host", host)
+    }
+
+    /// The port, or the default port of the scheme when none is given.
+    pub fn port(&self) -> u16 {
+        unsafe { TSUrlPortGet(self.bufp, self.urlp) as u16 }
+    }
+
+    pub fn set_port(&self, port: u16) -> Result<(), String> {
+        match unsafe { TSUrlPortSet(self.bufp, self.urlp, port as c_int) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set url port {}", port)),
+        }
+    }
+
+    /// The path, without its leading slash.
+    pub fn path(&self) -> Option<String> {
+        self.part(TSUrlPathGet)
+    }
+
+    pub fn set_path(&self, path: &str) -> Result<(), String> {
+        self.set_part(TSUrlPathSet, "path", path)
+    }
+
+    pub fn query(&self) -> Option<String> {
+        self.part(TSUrlHttpQueryGet)
+    }
+
+    pub fn set_query(&self, query: &str) -> Result<(), String> {
+        self.set_part(TSUrlHttpQuerySet, "query", query)
+    }
+
+    fn part(&self, get: PartGetter) -> Option<String> {
+        unsafe {
+            let mut len: c_int = 0;
+            let s = ts_string(get(self.bufp, self.urlp, &mut len), len)?;
+            if s.is_empty() {
+                None
+            } else {
+                Some(s)
+            }
+        }
+    }
+
+    fn set_part(&self, set: PartSetter, name: &str, value: &str) -> Result<(), String> {
+        match unsafe { set(self.bufp, self.urlp, value.as_ptr() as *const c_char, value.len() as c_int) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("unable to set url {} {}", name, value)),
+        }
+    }
+}
+
+impl Default for TsUrl {
+    fn default() -> TsUrl {
+        TsUrl::new()
+    }
+}
+
+impl Clone for TsUrl {
+    fn clone(&self) -> TsUrl {
+        unsafe {
+            let bufp = TSMBufferCreate();
+            let mut urlp: TSMLoc = ptr::null_mut();
+            TSUrlClone(bufp, self.bufp, self.urlp, &mut urlp);
+            TsUrl { bufp, urlp }
+        }
+    }
+}
+
+impl fmt::Display for TsUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut len: c_int = 0;
+        let s = unsafe { ts_owned_string(TSUrlStringGet(self.bufp, self.urlp, &mut len), len) };
+        f.write_str(&s.unwrap_or_default())
+    }
+}
+
+impl fmt::Debug for TsUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TsUrl").field(&self.to_string()).finish()
+    }
+}
+
+impl Drop for TsUrl {
+    fn drop(&mut self) {
+        unsafe {
+            TSUrlDestroy(self.bufp, self.urlp);
+            TSHandleMLocRelease(self.bufp, ptr::null_mut(), self.urlp);
+            TSMBufferDestroy(self.bufp);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::addr::{socket_addr, RawSockAddr};
+use crate::bindings::*;
+use crate::session::Session;
+use crate::ts_url::TsUrl;
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+/// A thin, copyable handle over a `TSHttpTxn`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    txn: TSHttpTxn,
+}
+
+// SAFETY: the handle itself is a plain pointer. The transaction state is
+// owned by the thread of its state machine, so methods must only be called
+// from hooks or from futures started with `Transaction::spawn`, which run
+// there, and only while the transaction is paused.
+unsafe impl Send for Transaction {}
+
+impl Transaction {
+    pub fn from_raw(txn: TSHttpTxn) -> Transaction {
+        Transaction { txn }
+    }
+
+    pub fn as_raw(&self) -> TSHttpTxn {
+        self.txn
+    }
+
+    /// Resumes the transaction after a hook callback.
+    pub fn reenable(&self) {
+        unsafe { TSHttpTxnReenable(self.txn, TSEvent_TS_EVENT_HTTP_CONTINUE) }
+    }
+
+    /// Resumes the transaction in an error state, ATS will send an error response.
+    pub fn reenable_error(&self) {
+        unsafe { TSHttpTxnReenable(self.txn, TSEvent_TS_EVENT_HTTP_ERROR) }
+    }
+
+    /// The id of the transaction, unique for the lifetime of the process.
+    pub fn id(&self) -> u64 {
+        unsafe { TSHttpTxnIdGet(self.txn) }
+    }
+
+    /// Whether debugging is enabled for the transaction or its session.
+    pub fn debug_enabled(&self) -> bool {
+        unsafe { TSHttpTxnDebugGet(self.txn) != 0 || self.session().debug_enabled() }
+    }
+
+    /// The client session the transaction belongs to.
+    pub fn session(&self) -> Session {
+        Session::from_raw(unsafe { TSHttpTxnSsnGet(self.txn) })
+    }
+
+    /// The address of the client.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSHttpTxnClientAddrGet(self.txn)) }
+    }
+
+    /// The local address the client connected to.
+    pub fn incoming_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSHttpTxnIncomingAddrGet(self.txn)) }
+    }
+
+    /// The local address used for the origin connection.
+    pub fn outgoing_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSHttpTxnOutgoingAddrGet(self.txn)) }
+    }
+
+    /// Binds the origin connection to a local address.
+    pub fn set_outgoing_addr(&self, addr: SocketAddr) -> Result<(), String> {
+        let raw = RawSockAddr::new(&addr);
+        match unsafe { TSHttpTxnOutgoingAddrSet(self.txn, raw.as_ptr()) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("failed to set outgoing address {}", addr)),
+        }
+    }
+
+    /// The address of the origin server, once it is known.
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSHttpTxnServerAddrGet(self.txn)) }
+    }
+
+    /// Sets the address of the origin server.
+    pub fn set_server_addr(&self, addr: SocketAddr) -> Result<(), String> {
+        let raw = RawSockAddr::new(&addr);
+        match unsafe { TSHttpTxnServerAddrSet(self.txn, raw.as_ptr()) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("failed to set server address {}", addr)),
+        }
+    }
+
+    /// Sends the request to `addr` instead of the origin ATS would resolve,
+    /// skipping its DNS lookup.
+    ///
+    /// Must be called before ATS connects upstream: from remap, or from a hook
+    /// up to and including `TS_HTTP_OS_DNS_HOOK`.
+    pub fn set_origin(&self, addr: SocketAddr) -> Result<(), String> {
+        self.set_server_addr(addr)
+    }
+
+    /// Like `set_origin`, also rewriting the `Host` header sent upstream to
+    /// `host`. Valid at the same hook points as `set_origin`.
+    pub fn set_origin_host(&self, addr: SocketAddr, host: &str) -> Result<(), String> {
+        let request = self.client_request().ok_or("client request not available")?;
+        self.set_origin(addr)?;
+        request.set_field("Host", host);
+        Ok(())
+    }
+
+    /// The address of the next hop, either a parent proxy or the origin.
+    pub fn next_hop_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSHttpTxnNextHopAddrGet(self.txn)) }
+    }
+
+    /// The parent proxy set for this transaction, if any.
+    pub fn parent_proxy(&self) -> Option<(String, u16)> {
+        let mut host: *const c_char = ptr::null();
+        let mut port: c_int = 0;
+
+        unsafe {
+            if TSHttpTxnParentProxyGet(self.txn, &mut host, &mut port) != TSReturnCode_TS_SUCCESS || host.is_null() {
+                return None;
+            }
+            let host = CStr::from_ptr(host).to_string_lossy().into_owned();
+            Some((host, port as u16))
+        }
+    }
+
+    /// Routes the request through the parent proxy at `host:port`,
+    /// overriding `parent.config`. Valid before `TS_HTTP_OS_DNS_HOOK`.
+    pub fn set_parent_proxy(&self, host: &str, port: u16) -> Result<(), String> {
+        let host = CString::new(host).map_err(|e| e.to_string())?;
+        unsafe { TSHttpTxnParentProxySet(self.txn, host.as_ptr(), port as c_int) };
+        Ok(())
+    }
+
+    /// The URL parent selection hashes on, which defaults to the request URL.
+    pub fn parent_selection_url(&self) -> Option<TsUrl> {
+        let url = TsUrl::new();
+        let (bufp, urlp) = url.as_raw();
+
+        match unsafe { TSHttpTxnParentSelectionUrlGet(self.txn, bufp, urlp) } {
+            TSReturnCode_TS_SUCCESS => Some(url),
+            _ => None,
+        }
+    }
+
+    /// Makes parent selection hash on `url` instead of the request URL, so
+    /// that related requests go to the same parent.
+    pub fn set_parent_selection_url(&self, url: &TsUrl) -> Result<(), String> {
+        let (bufp, urlp) = url.as_raw();
+
+        match unsafe { TSHttpTxnParentSelectionUrlSet(self.txn, bufp, urlp) } {
+            TSReturnCode_TS_SUCCESS => Ok(()),
+            _ => Err(format!("failed to set parent selection url {}", url)),
+        }
+    }
+}
+
+impl From<TSHttpTxn> for Transaction {
+    fn from(txn: TSHttpTxn) -> Transaction {
+        Transaction::from_raw(txn)
+    }
+}
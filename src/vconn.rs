@@ -0,0 +1,113 @@
+use crate::addr::socket_addr;
+use crate::bindings::*;
+use crate::buffer::{IOBuffer, IOBufferReader};
+use std::net::SocketAddr;
+use std::os::raw::c_int;
+
+/// A borrowed `TSVConn`. Closing it is up to the owner of the connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VConn {
+    vc: TSVConn,
+}
+
+// SAFETY: a `TSVConn` is a handle that ATS allows to use from any thread
+// holding the connection mutex, which is how the crate drives it.
+unsafe impl Send for VConn {}
+
+impl VConn {
+    pub fn from_raw(vc: TSVConn) -> VConn {
+        VConn { vc }
+    }
+
+    pub fn as_raw(&self) -> TSVConn {
+        self.vc
+    }
+
+    /// Starts reading up to `nbytes` into `buffer`, notifying `contp`.
+    ///
+    /// # Safety
+    ///
+    /// `contp` must be a live continuation that outlives the read.
+    pub unsafe fn read(&self, contp: TSCont, buffer: &IOBuffer, nbytes: i64) -> Vio {
+        Vio::from_raw(TSVConnRead(self.vc, contp, buffer.as_raw(), nbytes))
+    }
+
+    /// Starts writing `nbytes` from `reader`, notifying `contp`.
+    ///
+    /// # Safety
+    ///
+    /// `contp` must be a live continuation that outlives the write.
+    pub unsafe fn write(&self, contp: TSCont, reader: IOBufferReader, nbytes: i64) -> Vio {
+        Vio::from_raw(TSVConnWrite(self.vc, contp, reader.as_raw(), nbytes))
+    }
+
+    pub fn shutdown(&self, read: bool, write: bool) {
+        unsafe { TSVConnShutdown(self.vc, read as c_int, write as c_int) }
+    }
+
+    pub fn close(self) {
+        unsafe { TSVConnClose(self.vc) }
+    }
+
+    pub fn abort(self, error: i32) {
+        unsafe { TSVConnAbort(self.vc, error) }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        unsafe { TSVConnClosedGet(self.vc) != 0 }
+    }
+
+    /// The address of the peer, for network connections.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSNetVConnRemoteAddrGet(self.vc)) }
+    }
+
+    /// The local address, for network connections.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        unsafe { socket_addr(TSNetVConnLocalAddrGet(self.vc)) }
+    }
+}
+
+/// A borrowed `TSVIO`, describing an in-flight read or write.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Vio {
+    vio: TSVIO,
+}
+
+impl Vio {
+    pub fn from_raw(vio: TSVIO) -> Vio {
+        Vio { vio }
+    }
+
+    pub fn as_raw(&self) -> TSVIO {
+        self.vio
+    }
+
+    pub fn reenable(&self) {
+        unsafe { TSVIOReenable(self.vio) }
+    }
+
+    pub fn nbytes(&self) -> i64 {
+        unsafe { TSVIONBytesGet(self.vio) }
+    }
+
+    pub fn set_nbytes(&self, nbytes: i64) {
+        unsafe { TSVIONBytesSet(self.vio, nbytes) }
+    }
+
+    pub fn ndone(&self) -> i64 {
+        unsafe { TSVIONDoneGet(self.vio) }
+    }
+
+    pub fn set_ndone(&self, ndone: i64) {
+        unsafe { TSVIONDoneSet(self.vio, ndone) }
+    }
+
+    pub fn ntodo(&self) -> i64 {
+        unsafe { TSVIONTodoGet(self.vio) }
+    }
+
+    pub fn reader(&self) -> IOBufferReader {
+        IOBufferReader::from_raw(unsafe { TSVIOReaderGet(self.vio) })
+    }
+}